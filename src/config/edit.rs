@@ -0,0 +1,167 @@
+use std::path::Path;
+
+use color_eyre::Result;
+use eyre::eyre;
+use toml_edit::{ArrayOfTables, DocumentMut, Item, Value};
+
+use crate::enclosure::rule::Rule;
+
+/// Add a rule to the end of the `rules:` list of a config file, picking the
+/// format from its extension. YAML and TOML files keep their comments and
+/// formatting. JSON files are pretty-printed, since they can't have
+/// comments anyway.
+pub fn append_rule(path: &Path, contents: &str, rule: &Rule) -> Result<String> {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => append_toml_rule(contents, rule),
+        Some("json") => append_json_rule(contents, rule),
+        _ => append_yaml_rule(contents, rule),
+    }
+}
+
+/// YAML has no format-preserving editor, so the rule is serialised on its own
+/// and spliced in after the last line of the existing `rules:` list.
+fn append_yaml_rule(contents: &str, rule: &Rule) -> Result<String> {
+    let item = serde_yaml::to_string(&[rule])?;
+    let mut lines: Vec<String> = contents.lines().map(String::from).collect();
+
+    let Some(start) = lines.iter().position(|line| line.starts_with("rules:")) else {
+        let mut updated = contents.to_string();
+        if !updated.is_empty() && !updated.ends_with('\n') {
+            updated.push('\n');
+        }
+        updated.push_str("rules:\n");
+        updated.push_str(&item);
+        return Ok(updated);
+    };
+
+    let inline = lines[start]["rules:".len()..].trim_start();
+    if inline.starts_with("[]") {
+        lines[start] = "rules:".to_string();
+    } else if !inline.is_empty() && !inline.starts_with('#') {
+        return Err(eyre!(
+            "can't add to a `rules:` list that's written inline, please add the rule by hand"
+        ));
+    }
+
+    // The list ends at the next top-level key. Trailing blank lines and
+    // comments are left after the new rule, since they usually belong to
+    // whatever comes next.
+    let mut end = start + 1;
+    for (index, line) in lines.iter().enumerate().skip(start + 1) {
+        let trimmed = line.trim_start();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        if line.starts_with(char::is_whitespace) || line.starts_with('-') {
+            end = index + 1;
+        } else {
+            break;
+        }
+    }
+
+    let indent = lines[start + 1..end]
+        .iter()
+        .find(|line| line.trim_start().starts_with('-'))
+        .map(|line| line[..line.len() - line.trim_start().len()].to_string())
+        .unwrap_or_default();
+    let item_lines = item.lines().map(|line| format!("{indent}{line}"));
+    lines.splice(end..end, item_lines);
+
+    let mut updated = lines.join("\n");
+    updated.push('\n');
+    Ok(updated)
+}
+
+fn append_toml_rule(contents: &str, rule: &Rule) -> Result<String> {
+    let mut document: DocumentMut = contents.parse()?;
+    let table = toml_edit::ser::to_document(rule)?.as_table().clone();
+
+    match document.get_mut("rules") {
+        None => {
+            let mut rules = ArrayOfTables::new();
+            rules.push(table);
+            document.insert("rules", Item::ArrayOfTables(rules));
+        }
+        Some(Item::ArrayOfTables(rules)) => rules.push(table),
+        Some(Item::Value(Value::Array(rules))) => rules.push(table.into_inline_table()),
+        Some(_) => return Err(eyre!("`rules` is not a list of rules")),
+    }
+
+    Ok(document.to_string())
+}
+
+fn append_json_rule(contents: &str, rule: &Rule) -> Result<String> {
+    let mut document: serde_json::Value = if contents.trim().is_empty() {
+        serde_json::json!({})
+    } else {
+        serde_json::from_str(contents)?
+    };
+
+    let Some(document_map) = document.as_object_mut() else {
+        return Err(eyre!("config file is not a JSON object"));
+    };
+    let rules = document_map
+        .entry("rules")
+        .or_insert_with(|| serde_json::json!([]));
+    let Some(rules) = rules.as_array_mut() else {
+        return Err(eyre!("`rules` is not a list of rules"));
+    };
+    rules.push(serde_json::to_value(rule)?);
+
+    let mut updated = serde_json::to_string_pretty(&document)?;
+    updated.push('\n');
+    Ok(updated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use crate::enclosure::rule::{EnvValue, RuleMode};
+
+    fn rule() -> Rule {
+        Rule {
+            name: "kube".into(),
+            target: "~/.kube".into(),
+            rewrite: "~/.config/kube".into(),
+            mode: RuleMode::Directory,
+            only: vec!["kubectl".into()],
+            env: HashMap::from([("KUBECONFIG".into(), EnvValue::Set("x".into()))]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_append_yaml_rule_keeps_comments() -> Result<()> {
+        let contents = "# my rules\nrules:\n  # aws\n  - name: aws\n    target: ~/.aws\n    rewrite: ~/.config/aws\n\n# work stuff\nprofiles: {}\n";
+
+        let updated = append_yaml_rule(contents, &rule())?;
+
+        assert!(updated.starts_with("# my rules\nrules:\n  # aws\n  - name: aws\n"));
+        assert!(
+            updated.contains("    rewrite: ~/.config/aws\n  - name: kube\n    target: ~/.kube\n")
+        );
+        assert!(updated.ends_with("\n# work stuff\nprofiles: {}\n"));
+        let parsed: crate::enclosure::rule::BoxxyRules = serde_yaml::from_str(&updated)?;
+        assert_eq!(parsed.rules.len(), 2);
+        assert_eq!(parsed.rules[1].only, vec!["kubectl"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_append_toml_rule_keeps_comments() -> Result<()> {
+        let contents = "# my rules\n[[rules]]\nname = \"aws\" # aws\ntarget = \"~/.aws\"\nrewrite = \"~/.config/aws\"\n";
+
+        let updated = append_toml_rule(contents, &rule())?;
+
+        assert!(updated.starts_with(contents));
+        let parsed: crate::enclosure::rule::BoxxyRules = toml::from_str(&updated)?;
+        assert_eq!(parsed.rules.len(), 2);
+        assert_eq!(parsed.rules[1].name, "kube");
+
+        Ok(())
+    }
+}
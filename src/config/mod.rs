@@ -1,11 +1,21 @@
-use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
 use color_eyre::Result;
+use eyre::eyre;
 use log::*;
 
-use crate::enclosure::rule::{BoxxyRules, Rule};
+use crate::enclosure::fs::{append_all, FsDriver};
+use crate::enclosure::rule::{BoxxyRules, Profile, Rule};
+
+use self::validate::{Diagnostic, Severity, Validator};
+
+pub mod edit;
+pub mod validate;
+
+/// The config file formats boxxy understands, in the order they're loaded in
+/// when a directory has more than one.
+const CONFIG_EXTENSIONS: [&str; 3] = ["yaml", "toml", "json"];
 
 pub struct BoxxyConfig {
     pub rules: BoxxyRules,
@@ -41,37 +51,67 @@ impl BoxxyConfig {
         }
     }
 
-    pub fn rule_paths() -> Result<Vec<PathBuf>> {
-        let config_file_name = Self::default_config_file_name()?;
+    /// Every file name a config file can have in a directory, in the order
+    /// they're loaded in.
+    pub fn config_file_names() -> Result<Vec<String>> {
+        let stem = if Self::debug_mode()? {
+            "boxxy-dev"
+        } else {
+            "boxxy"
+        };
+        Ok(CONFIG_EXTENSIONS
+            .iter()
+            .map(|ext| format!("{stem}.{ext}"))
+            .collect())
+    }
 
-        let default_config_file = {
-            let config_dir = dirs::config_dir().unwrap();
-            let config_path =
-                crate::enclosure::fs::append_all(&config_dir, vec!["boxxy", config_file_name]);
+    pub fn rule_paths() -> Result<Vec<PathBuf>> {
+        let mut config_paths = Self::global_config_paths()?;
+        config_paths.extend(Self::conf_d_paths()?);
+        config_paths.extend(Self::project_config_paths()?);
 
-            std::fs::create_dir_all(config_path.parent().unwrap())?;
+        Ok(config_paths)
+    }
 
-            config_path
-        };
+    /// The config files in `~/.config/boxxy` that exist.
+    pub fn global_config_paths() -> Result<Vec<PathBuf>> {
+        let config_dir = append_all(&dirs::config_dir().unwrap(), vec!["boxxy"]);
+        std::fs::create_dir_all(&config_dir)?;
 
         let mut config_paths = vec![];
-        if default_config_file.exists() {
-            config_paths.push(default_config_file);
+        for config_file_name in Self::config_file_names()? {
+            let config_path = append_all(&config_dir, vec![config_file_name]);
+            if config_path.exists() {
+                config_paths.push(config_path);
+            }
         }
 
-        // Search up the tree for a `config_file_name` file
+        Ok(config_paths)
+    }
+
+    /// Config files in the current directory and its parents. They're
+    /// returned furthest-first, so that configs closer to the current
+    /// directory take precedence when merging.
+    pub fn project_config_paths() -> Result<Vec<PathBuf>> {
+        let config_file_names = Self::config_file_names()?;
+        let mut project_config_paths = vec![];
+
+        // Search up the tree for a config file
         let mut current_dir = std::env::current_dir()?;
         debug!(
             "searching for boxxy config starting at {}",
             current_dir.display()
         );
         loop {
-            let config_path =
-                crate::enclosure::fs::append_all(&current_dir, vec![config_file_name]);
-            debug!("checking for: {}", config_path.display());
-            if config_path.exists() {
-                debug!("found boxxy config file at {}", config_path.display());
-                config_paths.push(config_path);
+            // Reversed, so that the order is right after reversing the
+            // whole list below.
+            for config_file_name in config_file_names.iter().rev() {
+                let config_path = append_all(&current_dir, vec![config_file_name]);
+                debug!("checking for: {}", config_path.display());
+                if config_path.exists() {
+                    debug!("found boxxy config file at {}", config_path.display());
+                    project_config_paths.push(config_path);
+                }
             }
 
             if let Some(parent) = current_dir.parent() {
@@ -85,81 +125,357 @@ impl BoxxyConfig {
                 break;
             }
         }
+        project_config_paths.reverse();
 
-        Ok(config_paths)
+        Ok(project_config_paths)
     }
 
-    pub fn load_rules_from_path(path: &Path) -> Result<BoxxyRules> {
-        let config = config::Config::builder()
-            .add_source(config::File::new(
-                &path.to_string_lossy(),
-                config::FileFormat::Yaml,
-            ))
-            .build()?;
+    /// Config files in `~/.config/boxxy/conf.d`, in lexical order.
+    pub fn conf_d_paths() -> Result<Vec<PathBuf>> {
+        let config_dir = dirs::config_dir().unwrap();
+        let conf_d = crate::enclosure::fs::append_all(&config_dir, vec!["boxxy", "conf.d"]);
 
-        let rules = config.try_deserialize::<BoxxyRules>()?;
+        let mut conf_d_paths = vec![];
+        if conf_d.is_dir() {
+            for entry in std::fs::read_dir(&conf_d)? {
+                let path = entry?.path();
+                let has_config_extension = path
+                    .extension()
+                    .is_some_and(|ext| CONFIG_EXTENSIONS.iter().any(|e| ext == *e));
+                if path.is_file() && has_config_extension {
+                    debug!("found conf.d config file at {}", path.display());
+                    conf_d_paths.push(path);
+                }
+            }
+        }
+        conf_d_paths.sort();
 
+        Ok(conf_d_paths)
+    }
+
+    /// The JSON Schema for config files, generated from the rule types so
+    /// that it always matches what boxxy accepts.
+    pub fn json_schema() -> Result<String> {
+        Ok(serde_json::to_string_pretty(&schemars::schema_for!(
+            BoxxyRules
+        ))?)
+    }
+
+    pub fn load_rules_from_path(path: &Path) -> Result<BoxxyRules> {
+        let mut diagnostics = vec![];
+        let rules = Self::do_load_rules_from_path(path, 0, &mut diagnostics)?;
+        Self::check_diagnostics(diagnostics)?;
         Ok(rules)
     }
 
+    /// Load a config file, along with anything it includes, and return every
+    /// problem found in it. Fails if the config can't be parsed at all.
+    pub fn validate_path(path: &Path) -> Result<Vec<Diagnostic>> {
+        let mut diagnostics = vec![];
+        Self::do_load_rules_from_path(path, 0, &mut diagnostics)?;
+        Ok(diagnostics)
+    }
+
+    /// Fail with every error found by validation. Warnings are only logged
+    /// in debug mode, and are shown in full by `boxxy config validate`.
+    fn check_diagnostics(diagnostics: Vec<Diagnostic>) -> Result<()> {
+        let mut errors = vec![];
+        for diagnostic in diagnostics {
+            match diagnostic.severity {
+                Severity::Warning => debug!("{diagnostic}"),
+                Severity::Error => errors.push(diagnostic.to_string()),
+            }
+        }
+        if !errors.is_empty() {
+            return Err(eyre!(
+                "invalid config, run `boxxy config validate` to check it:\n{}",
+                errors.join("\n")
+            ));
+        }
+        Ok(())
+    }
+
+    fn do_load_rules_from_path(
+        path: &Path,
+        depth: u32,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> Result<BoxxyRules> {
+        if depth > 10 {
+            return Err(eyre!(
+                "Too many nested includes when loading config: {path:?}"
+            ));
+        }
+
+        let contents = std::fs::read_to_string(path)?;
+        let mut rules = Self::parse_rules(path, &contents)?;
+        diagnostics
+            .extend(Validator::new(path.display().to_string(), Some(&contents)).validate(&rules));
+        for rule in rules
+            .rules
+            .iter_mut()
+            .chain(rules.profiles.values_mut().flat_map(|p| p.rules.iter_mut()))
+        {
+            rule.source = Some(path.to_path_buf());
+        }
+        if rules.include.is_empty() {
+            return Ok(rules);
+        }
+
+        // Included rules come first, so that the including file can override
+        // them.
+        let mut configs = vec![];
+        for include in std::mem::take(&mut rules.include) {
+            for include_path in Self::resolve_include(path, &include)? {
+                debug!("including rules from {}", include_path.display());
+                configs.push(Self::do_load_rules_from_path(
+                    &include_path,
+                    depth + 1,
+                    diagnostics,
+                )?);
+            }
+        }
+        configs.push(rules);
+
+        Ok(Self::merge(configs))
+    }
+
+    /// Parse the rules in a config file, picking the format from its
+    /// extension. Anything that isn't `.toml` or `.json` is read as YAML.
+    fn parse_rules(path: &Path, contents: &str) -> Result<BoxxyRules> {
+        if contents.trim().is_empty() {
+            return Ok(BoxxyRules::default());
+        }
+
+        let rules = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(contents).map_err(|e| eyre!(e.to_string())),
+            Some("json") => serde_json::from_str(contents).map_err(|e| eyre!(e.to_string())),
+            _ => serde_yaml::from_str(contents).map_err(|e| eyre!(e.to_string())),
+        };
+        rules.map_err(|e| eyre!("{}: {e}", path.display()))
+    }
+
+    /// Resolve an `include:` pattern to the matching files. Relative patterns
+    /// are resolved relative to the directory of the including config file.
+    fn resolve_include(config_path: &Path, include: &str) -> Result<Vec<PathBuf>> {
+        let include = PathBuf::from(shellexpand::tilde(include).to_string());
+        let include = match config_path.parent() {
+            Some(config_dir) if include.is_relative() => config_dir.join(include),
+            _ => include,
+        };
+
+        let mut include_paths = vec![];
+        for include_path in glob::glob(&include.to_string_lossy())? {
+            include_paths.push(include_path?);
+        }
+        include_paths.sort();
+
+        Ok(include_paths)
+    }
+
     pub fn load_rules_from_cli_flag(rules: &[String]) -> Result<BoxxyRules> {
         let rules = rules
             .iter()
             .map(|s| {
                 let parts: Vec<&str> = s.split(':').collect();
-                match parts.as_slice() {
+                let rule = match parts.as_slice() {
                     [src, dest] => Rule {
                         name: format!("cli-loaded rule: {src} -> {dest}"),
                         target: src.to_string(),
                         rewrite: dest.to_string(),
-                        mode: crate::enclosure::rule::RuleMode::File,
-                        context: vec![],
-                        only: vec![],
-                        env: HashMap::new(),
+                        ..Default::default()
                     },
 
                     [src, dest, mode] => Rule {
                         name: format!("cli-loaded rule: {src} -> {dest} ({mode})"),
                         target: src.to_string(),
                         rewrite: dest.to_string(),
-                        mode: mode.parse().unwrap(),
-                        context: vec![],
-                        only: vec![],
-                        env: HashMap::new(),
+                        mode: mode
+                            .parse()
+                            .map_err(|e| eyre!("invalid cli rule {s:?}: {e}"))?,
+                        ..Default::default()
                     },
 
-                    _ => panic!("invalid format for cli rule: {s}"),
-                }
+                    _ => {
+                        return Err(eyre!(
+                            "invalid cli rule {s:?}, expected `target:rewrite` or `target:rewrite:mode`"
+                        ))
+                    }
+                };
+                Ok(rule)
             })
-            .collect();
-        Ok(BoxxyRules { rules })
+            .collect::<Result<Vec<_>>>()?;
+        let rules = BoxxyRules {
+            rules,
+            ..Default::default()
+        };
+        Self::check_diagnostics(Validator::new("--rule".into(), None).validate(&rules))?;
+
+        Ok(rules)
+    }
+
+    /// Add a rule to the global config, or to the nearest project config if
+    /// `project` is set, creating the config file if there isn't one yet.
+    /// Returns the path of the config file that was changed.
+    /// Refuse to add a rule whose name is already taken in a config file, or
+    /// whose target already has a rule, since one of them would silently
+    /// shadow the other.
+    fn check_new_rule(existing: &BoxxyRules, rule: &Rule, path: &Path) -> Result<()> {
+        let mut existing_rules = existing
+            .rules
+            .iter()
+            .chain(existing.profiles.values().flat_map(|p| p.rules.iter()));
+        if existing_rules.any(|r| r.name == rule.name) {
+            return Err(eyre!(
+                "{} already has a rule named '{}'",
+                path.display(),
+                rule.name
+            ));
+        }
+
+        // Profile rules are meant to override the top-level rules, so only
+        // those can clash with the new rule.
+        let fs = FsDriver::new();
+        let target = fs.fully_expand_path(&rule.target)?;
+        for existing_rule in &existing.rules {
+            if fs.fully_expand_path(&existing_rule.target)? == target {
+                return Err(eyre!(
+                    "{} already has a rule for {}: '{}'",
+                    path.display(),
+                    target.display(),
+                    existing_rule.name
+                ));
+            }
+        }
+
+        Ok(())
     }
 
+    pub fn add_rule(rule: &Rule, project: bool) -> Result<PathBuf> {
+        let path = if project {
+            match Self::project_config_paths()?.pop() {
+                Some(path) => path,
+                None => append_all(
+                    &std::env::current_dir()?,
+                    vec![Self::default_config_file_name()?],
+                ),
+            }
+        } else {
+            match Self::global_config_paths()?.pop() {
+                Some(path) => path,
+                None => Self::default_config_path()?,
+            }
+        };
+
+        let existed = path.exists();
+        let contents = if existed {
+            std::fs::read_to_string(&path)?
+        } else {
+            String::new()
+        };
+        if existed {
+            Self::check_new_rule(&Self::load_rules_from_path(&path)?, rule, &path)?;
+        }
+
+        let new_rules = BoxxyRules {
+            rules: vec![rule.clone()],
+            ..Default::default()
+        };
+        let mut errors = vec![];
+        for diagnostic in Validator::new(path.display().to_string(), None).validate(&new_rules) {
+            match diagnostic.severity {
+                Severity::Warning => warn!("{diagnostic}"),
+                Severity::Error => errors.push(diagnostic.to_string()),
+            }
+        }
+        if !errors.is_empty() {
+            return Err(eyre!("invalid rule:\n{}", errors.join("\n")));
+        }
+
+        std::fs::write(&path, edit::append_rule(&path, &contents, rule)?)?;
+
+        // Make sure the edit left a config that boxxy can still load.
+        if let Err(e) = Self::load_rules_from_path(&path) {
+            if existed {
+                std::fs::write(&path, &contents)?;
+            } else {
+                std::fs::remove_file(&path)?;
+            }
+            return Err(e.wrap_err(format!(
+                "adding the rule broke {}, so it was left unchanged",
+                path.display()
+            )));
+        }
+
+        Ok(path)
+    }
+
+    /// Merge rule sets in order of increasing precedence. A rule replaces
+    /// any earlier rule with the same name, so project configs override the
+    /// global config, and CLI rules override everything.
     pub fn merge(configs: Vec<BoxxyRules>) -> BoxxyRules {
-        let mut merged = BoxxyRules { rules: vec![] };
+        let mut merged = BoxxyRules::default();
         for config in configs {
-            merged.rules.extend(config.rules);
+            for (name, profile) in config.profiles {
+                match merged.profiles.get_mut(&name) {
+                    Some(merged_profile) => merged_profile.extend(profile),
+                    None => {
+                        merged.profiles.insert(name, profile);
+                    }
+                }
+            }
+            for rule in config.rules {
+                if let Some(index) = merged.rules.iter().position(|r| r.name == rule.name) {
+                    debug!("{}: overriding earlier rule with the same name", rule.name);
+                    merged.rules.remove(index);
+                }
+                merged.rules.push(rule);
+            }
         }
 
         merged
     }
 
-    pub fn load_config(args: crate::Args) -> Result<Self> {
-        // Load rules
-        let rules = {
-            let mut rules = vec![];
-            if !args.no_config {
-                debug!("loading rules (not asked not to!)");
-                for config in BoxxyConfig::rule_paths()? {
-                    info!("loading rules from {}", config.display());
-                    rules.push(BoxxyConfig::load_rules_from_path(&config)?);
+    /// Load and merge the rules from every config file and the CLI, along
+    /// with the settings of the active profile, if any.
+    pub fn load_rules(args: &crate::Args) -> Result<(BoxxyRules, Profile)> {
+        let mut rules = vec![];
+        let mut active_profile = Profile::default();
+        let mut found_profile = false;
+        if !args.no_config {
+            debug!("loading rules (not asked not to!)");
+            for config in BoxxyConfig::rule_paths()? {
+                info!("loading rules from {}", config.display());
+                let mut config_rules = BoxxyConfig::load_rules_from_path(&config)?;
+                if let Some(profile_name) = &args.profile {
+                    if let Some(profile) = config_rules.activate_profile(profile_name) {
+                        debug!("loaded profile '{profile_name}' from {}", config.display());
+                        found_profile = true;
+                        active_profile.extend(profile);
+                    }
                 }
+                rules.push(config_rules);
             }
-            rules.push(BoxxyConfig::load_rules_from_cli_flag(&args.arg_rules)?);
-            BoxxyConfig::merge(rules)
-        };
+        }
+        if let Some(profile_name) = &args.profile {
+            if !found_profile {
+                return Err(eyre!(
+                    "profile '{profile_name}' is not defined in any config file"
+                ));
+            }
+            info!("using profile '{profile_name}'");
+        }
+        rules.push(BoxxyConfig::load_rules_from_cli_flag(&args.arg_rules)?);
+        let rules = BoxxyConfig::merge(rules);
         info!("loaded {} total rule(s)", rules.rules.len());
 
+        Ok((rules, active_profile))
+    }
+
+    pub fn load_config(args: crate::Args) -> Result<Self> {
+        let (rules, profile) = BoxxyConfig::load_rules(&args)?;
+        let immutable_root = args.immutable_root || profile.immutable;
+        let dotenv = args.dotenv || profile.dotenv;
+
         let (cmd, cmd_args) = (&args.command_with_args[0], &args.command_with_args[1..]);
 
         if which::which(cmd).is_err() {
@@ -175,6 +491,7 @@ impl BoxxyConfig {
 
         // Pass through current env
         command.envs(std::env::vars());
+        command.envs(profile.env);
 
         // Pass args
         if !cmd_args.is_empty() {
@@ -183,11 +500,211 @@ impl BoxxyConfig {
 
         Ok(Self {
             rules,
-            immutable_root: args.immutable_root,
+            immutable_root,
             trace: args.trace,
-            dotenv: args.dotenv,
+            dotenv,
             daemon: args.daemon,
             command,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use color_eyre::Result;
+
+    fn rules(yaml: &str) -> Result<BoxxyRules> {
+        Ok(serde_yaml::from_str(yaml)?)
+    }
+
+    #[test]
+    fn test_new_rules_cant_reuse_names_or_targets() -> Result<()> {
+        let existing: BoxxyRules =
+            serde_yaml::from_str("rules:\n- name: c\n  target: ~/.c\n  rewrite: ~/.config/c\n")?;
+        let path = Path::new("boxxy.yaml");
+        let new_rule = |name: &str, target: &str| Rule {
+            name: name.into(),
+            target: target.into(),
+            mode: crate::enclosure::rule::RuleMode::Tmpfs,
+            ..Default::default()
+        };
+
+        let same_name = BoxxyConfig::check_new_rule(&existing, &new_rule("c", "~/.d"), path);
+        let same_target = BoxxyConfig::check_new_rule(&existing, &new_rule("d", "~/.c"), path);
+
+        assert!(same_name
+            .unwrap_err()
+            .to_string()
+            .contains("rule named 'c'"));
+        assert!(same_target
+            .unwrap_err()
+            .to_string()
+            .contains("already has a rule for"));
+        assert!(BoxxyConfig::check_new_rule(&existing, &new_rule("d", "~/.d"), path).is_ok());
+        assert!(!serde_yaml::to_string(&new_rule("d", "~/.d"))?.contains("rewrite"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_overrides_rules_by_name() -> Result<()> {
+        let global = rules(
+            r#"
+            rules:
+            - name: "aws"
+              target: "~/.aws"
+              rewrite: "~/.config/aws"
+            - name: "kube"
+              target: "~/.kube"
+              rewrite: "~/.config/kube"
+            "#,
+        )?;
+        let project = rules(
+            r#"
+            rules:
+            - name: "aws"
+              target: "~/.aws"
+              rewrite: "./.aws"
+            "#,
+        )?;
+
+        let merged = BoxxyConfig::merge(vec![global, project]);
+        let names: Vec<_> = merged.rules.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, vec!["kube", "aws"]);
+        assert_eq!(merged.rules[1].rewrite, "./.aws");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_includes_are_merged_below_the_including_file() -> Result<()> {
+        let driver = crate::enclosure::fs::FsDriver::new();
+        let name = "test-config-includes";
+        let root = driver.container_root(name);
+        driver.setup_root(name)?;
+        driver.touch_dir(&root.join("rules.d"))?;
+        std::fs::write(
+            root.join("boxxy.yaml"),
+            r#"
+            include: ["rules.d/*.yaml"]
+            rules:
+            - name: "aws"
+              target: "~/.aws"
+              rewrite: "~/.config/aws"
+            "#,
+        )?;
+        for (file, rule) in [("10-aws.yaml", "aws"), ("20-kube.yaml", "kube")] {
+            std::fs::write(
+                root.join("rules.d").join(file),
+                format!("rules:\n- name: {rule}\n  target: ~/.{rule}\n  rewrite: ./{rule}\n"),
+            )?;
+        }
+
+        let rules = BoxxyConfig::load_rules_from_path(&root.join("boxxy.yaml"));
+        driver.cleanup_root(name)?;
+
+        let rules = rules?;
+        let names: Vec<_> = rules.rules.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, vec!["kube", "aws"]);
+        assert_eq!(rules.rules[1].rewrite, "~/.config/aws");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_reports_mistakes_with_line_numbers() -> Result<()> {
+        let driver = crate::enclosure::fs::FsDriver::new();
+        let name = "test-config-validate";
+        let root = driver.container_root(name);
+        driver.setup_root(name)?;
+        std::fs::write(
+            root.join("boxxy.yaml"),
+            "rules:\n- name: aws\n  target: ~/.aws\n  rewrite: ~/.config/aws\n- name: aws\n  target: ~/.aws\n  rewrite: /tmp\n  mode: file\n",
+        )?;
+        std::fs::write(
+            root.join("typo.yaml"),
+            "rules:\n- name: aws\n  target: ~/.aws\n  rewirte: ~/.config/aws\n",
+        )?;
+
+        let diagnostics = BoxxyConfig::validate_path(&root.join("boxxy.yaml"));
+        let typo = BoxxyConfig::validate_path(&root.join("typo.yaml"));
+        driver.cleanup_root(name)?;
+
+        let messages: Vec<_> = diagnostics?
+            .iter()
+            .filter(|d| d.severity == Severity::Error)
+            .map(|d| (d.line, d.message.clone()))
+            .collect();
+        assert_eq!(messages.len(), 2);
+        assert!(messages.iter().all(|(line, _)| *line == Some(5)));
+        assert!(messages[0].1.contains("same name"));
+        assert!(messages[1].1.contains("is a directory"));
+
+        let typo = typo.unwrap_err().to_string();
+        assert!(typo.contains("unknown field `rewirte`"));
+        assert!(typo.contains("line 4"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cli_rules_reject_bad_formats() {
+        assert!(BoxxyConfig::load_rules_from_cli_flag(&["~/.aws:~/.config/aws".into()]).is_ok());
+        assert!(BoxxyConfig::load_rules_from_cli_flag(&["~/.aws".into()]).is_err());
+        assert!(BoxxyConfig::load_rules_from_cli_flag(&["~/.aws:~/x:nope".into()]).is_err());
+    }
+
+    #[test]
+    fn test_json_schema_matches_rule_types() -> Result<()> {
+        let schema: serde_json::Value = serde_json::from_str(&BoxxyConfig::json_schema()?)?;
+        let rule = &schema["$defs"]["Rule"];
+
+        assert_eq!(rule["additionalProperties"], false);
+        assert_eq!(rule["required"], serde_json::json!(["name", "target"]));
+        assert!(rule["properties"]["target"]["description"]
+            .as_str()
+            .is_some_and(|d| d.contains("shadowed")));
+        assert!(rule["properties"].get("source").is_none());
+        assert!(schema["$defs"]["RuleMode"].to_string().contains("overlay"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_format_is_picked_by_extension() -> Result<()> {
+        let driver = crate::enclosure::fs::FsDriver::new();
+        let name = "test-config-formats";
+        let root = driver.container_root(name);
+        driver.setup_root(name)?;
+        std::fs::write(
+            root.join("boxxy.toml"),
+            "[[rules]]\nname = \"aws\"\ntarget = \"~/.aws\"\nrewrite = \"~/.config/aws\"\nonly = [\"aws\"]\n",
+        )?;
+        std::fs::write(
+            root.join("boxxy.json"),
+            r#"{"rules": [{"name": "aws", "target": "~/.aws", "rewrite": "~/.config/aws", "only": ["aws"]}]}"#,
+        )?;
+        std::fs::write(
+            root.join("bad.toml"),
+            "[[rules]]\nname = \"aws\"\ntargt = 1\n",
+        )?;
+
+        let toml = BoxxyConfig::load_rules_from_path(&root.join("boxxy.toml"));
+        let json = BoxxyConfig::load_rules_from_path(&root.join("boxxy.json"));
+        let bad = BoxxyConfig::load_rules_from_path(&root.join("bad.toml"));
+        driver.cleanup_root(name)?;
+
+        for rules in [toml?, json?] {
+            assert_eq!(rules.rules.len(), 1);
+            assert_eq!(rules.rules[0].rewrite, "~/.config/aws");
+            assert_eq!(rules.rules[0].only, vec!["aws"]);
+        }
+        let bad = bad.unwrap_err().to_string();
+        assert!(bad.contains("unknown field `targt`"));
+        assert!(bad.contains("line 3"));
+
+        Ok(())
+    }
+}
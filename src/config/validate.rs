@@ -0,0 +1,603 @@
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use regex::Regex;
+
+use crate::enclosure::fs::FsDriver;
+use crate::enclosure::rule::{BoxxyRules, Rule, RuleMode};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// The config is usable, but probably doesn't do what was intended.
+    Warning,
+    /// The config is broken, and boxxy refuses to use it.
+    Error,
+}
+
+/// A problem found in a config file.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// The config file, or `--rule` for rules passed at the command line.
+    pub source: String,
+    pub line: Option<usize>,
+    pub rule: String,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)?;
+        if let Some(line) = self.line {
+            write!(f, ":{line}")?;
+        }
+        write!(f, ": rule '{}': {}", self.rule, self.message)
+    }
+}
+
+/// Checks the rules of a single config file for mistakes that deserializing
+/// can't catch. `contents` is the text of the config file, and is used to
+/// find the line each rule starts on.
+pub struct Validator {
+    source: String,
+    rule_names: Vec<RuleName>,
+    fs: FsDriver,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Validator {
+    pub fn new(source: String, contents: Option<&str>) -> Self {
+        let rule_names = match contents {
+            Some(contents) => rule_names(&source, contents),
+            None => vec![],
+        };
+        Self {
+            source,
+            rule_names,
+            fs: FsDriver::new(),
+            diagnostics: vec![],
+        }
+    }
+
+    pub fn validate(mut self, rules: &BoxxyRules) -> Vec<Diagnostic> {
+        self.validate_rule_list(None, &rules.rules);
+        let mut profile_names: Vec<_> = rules.profiles.keys().collect();
+        profile_names.sort();
+        for name in profile_names {
+            self.validate_rule_list(Some(name), &rules.profiles[name].rules);
+        }
+
+        self.diagnostics
+    }
+
+    fn validate_rule_list(&mut self, profile: Option<&str>, rules: &[Rule]) {
+        let mut seen_names = HashSet::new();
+        let mut occurrences = std::collections::HashMap::new();
+        for rule in rules {
+            let occurrence = occurrences.entry(rule.name.as_str()).or_insert(0);
+            let line = self.rule_line(profile, &rule.name, *occurrence);
+            *occurrence += 1;
+
+            if !seen_names.insert(rule.name.as_str()) {
+                self.push(
+                    Severity::Error,
+                    line,
+                    rule,
+                    "another rule in this file has the same name, so only the last one is used",
+                );
+            }
+            self.validate_rule(rule, line);
+        }
+    }
+
+    fn validate_rule(&mut self, rule: &Rule, line: Option<usize>) {
+        let needs_rewrite = !matches!(rule.mode, RuleMode::Tmpfs | RuleMode::Hide);
+        if needs_rewrite && rule.rewrite.is_empty() {
+            self.push(
+                Severity::Error,
+                line,
+                rule,
+                format!("`{}` rules need a rewrite", rule.mode),
+            );
+        }
+        if rule.size.is_some() && rule.mode != RuleMode::Tmpfs {
+            self.push(
+                Severity::Warning,
+                line,
+                rule,
+                "`size` is only used by `tmpfs` rules",
+            );
+        }
+        if let Some(size) = &rule.size {
+            if !is_tmpfs_size(size) {
+                self.push(
+                    Severity::Error,
+                    line,
+                    rule,
+                    format!("`size` must be a number of bytes, optionally ending in k, m, g or %, not '{size}'"),
+                );
+            }
+        }
+        if rule.file && rule.mode != RuleMode::Tmpfs {
+            self.push(
+                Severity::Warning,
+                line,
+                rule,
+                "`file` is only used by `tmpfs` rules",
+            );
+        }
+        if !rule.options.is_empty() && rule.mode == RuleMode::Hide {
+            self.push(
+                Severity::Warning,
+                line,
+                rule,
+                "`options` are not used by `hide` rules",
+            );
+        }
+        if let Some(seed) = rule.seed {
+            if matches!(
+                rule.mode,
+                RuleMode::Overlay | RuleMode::Tmpfs | RuleMode::Hide
+            ) {
+                self.push(
+                    Severity::Warning,
+                    line,
+                    rule,
+                    format!("`seed: {seed}` is not used by `{}` rules", rule.mode),
+                );
+            }
+        }
+
+        if rule.has_glob_target() {
+            if let Err(e) = glob::Pattern::new(&rule.target) {
+                self.push(
+                    Severity::Error,
+                    line,
+                    rule,
+                    format!("invalid target glob: {e}"),
+                );
+            }
+        }
+        for pattern in rule.only.iter().chain(&rule.except) {
+            if let Some(regex) = pattern.strip_prefix("regex:") {
+                if let Err(e) = Regex::new(regex) {
+                    self.push(
+                        Severity::Error,
+                        line,
+                        rule,
+                        format!("invalid regex {pattern:?}: {e}"),
+                    );
+                }
+            } else if pattern.contains(['*', '?', '[']) {
+                if let Err(e) = glob::Pattern::new(pattern) {
+                    self.push(
+                        Severity::Error,
+                        line,
+                        rule,
+                        format!("invalid glob {pattern:?}: {e}"),
+                    );
+                }
+            }
+        }
+        for binary in rule.only.iter().filter(|b| rule.except.contains(b)) {
+            self.push(
+                Severity::Warning,
+                line,
+                rule,
+                format!(
+                    "{binary:?} is in both `only` and `except`, so the rule never applies to it"
+                ),
+            );
+        }
+        for arg in &rule.args {
+            if let Err(e) = glob::Pattern::new(arg) {
+                self.push(
+                    Severity::Error,
+                    line,
+                    rule,
+                    format!("invalid args glob {arg:?}: {e}"),
+                );
+            }
+        }
+
+        // The remaining checks look at the filesystem, so they need the
+        // expanded paths.
+        let rule = match rule.expand_variables(OsStr::new("")) {
+            Ok(rule) => rule,
+            Err(e) => {
+                self.push(
+                    Severity::Warning,
+                    line,
+                    rule,
+                    format!("couldn't expand variables, so boxxy will skip this rule: {e}"),
+                );
+                return;
+            }
+        };
+        self.validate_contexts(&rule, line);
+        if !rule.has_glob_target() {
+            self.validate_paths(&rule, line);
+        }
+    }
+
+    fn validate_contexts(&mut self, rule: &Rule, line: Option<usize>) {
+        for context in &rule.context {
+            if rule.not_context.contains(context) {
+                self.push(
+                    Severity::Error,
+                    line,
+                    rule,
+                    format!("context {context:?} is also in `not_context`, so the rule can never apply there"),
+                );
+                continue;
+            }
+            if context.starts_with("git:") || context.starts_with("marker:") {
+                continue;
+            }
+
+            let expanded = shellexpand::tilde(context).to_string();
+            let exists = if expanded.contains(['*', '?', '[']) {
+                match glob::glob(&expanded) {
+                    Ok(mut paths) => paths.next().is_some(),
+                    Err(e) => {
+                        self.push(
+                            Severity::Error,
+                            line,
+                            rule,
+                            format!("invalid context glob {context:?}: {e}"),
+                        );
+                        continue;
+                    }
+                }
+            } else {
+                PathBuf::from(&expanded).exists()
+            };
+            if !exists {
+                self.push(
+                    Severity::Warning,
+                    line,
+                    rule,
+                    format!("context {context:?} doesn't exist, so the rule can't apply there"),
+                );
+            }
+        }
+    }
+
+    fn validate_paths(&mut self, rule: &Rule, line: Option<usize>) {
+        let Ok(target) = self.fs.fully_expand_path(&rule.target) else {
+            return;
+        };
+        let Ok(rewrite) = self.fs.fully_expand_path(&rule.rewrite) else {
+            return;
+        };
+
+        let mut paths = vec![("target", target)];
+        if !rule.rewrite.is_empty() {
+            paths.push(("rewrite", rewrite));
+        }
+        for (kind, path) in paths {
+            let message = match rule.mode {
+                RuleMode::File if path.is_dir() => "is a directory, but this is a `file` rule",
+                RuleMode::Directory | RuleMode::Overlay if path.is_file() => {
+                    "is a file, but this rule needs a directory"
+                }
+                _ => continue,
+            };
+            self.push(
+                Severity::Error,
+                line,
+                rule,
+                format!("{kind} {} {message}", path.display()),
+            );
+        }
+    }
+
+    /// The line of the `name` key of the nth rule with this name in the
+    /// top-level rules or a profile's rules, if the config file's contents
+    /// are known. Lines with more than one rule on them, like in minified
+    /// JSON, don't point at any rule in particular, so they're left out.
+    fn rule_line(&self, profile: Option<&str>, name: &str, occurrence: usize) -> Option<usize> {
+        let line = self
+            .rule_names
+            .iter()
+            .filter(|rule_name| rule_name.profile.as_deref() == profile && rule_name.name == name)
+            .nth(occurrence)?
+            .line;
+        let rules_on_line = self
+            .rule_names
+            .iter()
+            .filter(|rule_name| rule_name.line == line)
+            .count();
+
+        (rules_on_line == 1).then_some(line)
+    }
+
+    fn push<S: Into<String>>(
+        &mut self,
+        severity: Severity,
+        line: Option<usize>,
+        rule: &Rule,
+        message: S,
+    ) {
+        self.diagnostics.push(Diagnostic {
+            severity,
+            source: self.source.clone(),
+            line,
+            rule: rule.name.clone(),
+            message: message.into(),
+        });
+    }
+}
+
+/// Where the `name` of a rule is in a config file, and which list of rules
+/// it's in.
+#[derive(Debug, PartialEq, Eq)]
+struct RuleName {
+    line: usize,
+    /// The profile whose rules this rule is in, or `None` for the top-level
+    /// rules.
+    profile: Option<String>,
+    name: String,
+}
+
+/// Find the `name` of every rule in a config file. The deserializers don't
+/// keep track of where values came from, so this is a rough scan of the
+/// file's text, picking the format from the extension like
+/// `BoxxyConfig::parse_rules` does.
+fn rule_names(source: &str, contents: &str) -> Vec<RuleName> {
+    match Path::new(source).extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => toml_rule_names(contents),
+        Some("json") => json_rule_names(contents),
+        _ => yaml_rule_names(contents),
+    }
+}
+
+/// Which list of rules a path of keys points to: `Some(None)` for the
+/// top-level rules, `Some(Some(profile))` for a profile's rules.
+fn rule_list_scope<S: AsRef<str>>(keys: &[S]) -> Option<Option<String>> {
+    match keys {
+        [rules] if rules.as_ref() == "rules" => Some(None),
+        [profiles, profile, rules]
+            if profiles.as_ref() == "profiles" && rules.as_ref() == "rules" =>
+        {
+            Some(Some(profile.as_ref().to_string()))
+        }
+        _ => None,
+    }
+}
+
+/// A scalar value without its quotes or trailing comment.
+fn scalar(value: &str) -> &str {
+    let value = value.trim();
+    for quote in ['"', '\''] {
+        if let Some(rest) = value.strip_prefix(quote) {
+            return rest.split(quote).next().unwrap_or_default();
+        }
+    }
+    value.split(" #").next().unwrap_or_default().trim()
+}
+
+fn yaml_rule_names(contents: &str) -> Vec<RuleName> {
+    let mut names = vec![];
+    // The keys of the block mappings the current line is in, along with
+    // their indentation.
+    let mut keys: Vec<(usize, &str)> = vec![];
+    for (index, line) in contents.lines().enumerate() {
+        let trimmed = line.trim_start();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+
+        let mut indent = line.len() - trimmed.len();
+        let mut rest = trimmed;
+        if let Some(item) = rest
+            .strip_prefix('-')
+            .filter(|item| item.is_empty() || item.starts_with(' '))
+        {
+            // List items may be indented as far as the key they belong to.
+            keys.retain(|(key_indent, _)| *key_indent <= indent);
+            rest = item.trim_start();
+            indent = line.len() - rest.len();
+        } else {
+            keys.retain(|(key_indent, _)| *key_indent < indent);
+        }
+
+        let Some((key, value)) = rest.split_once(':') else {
+            continue;
+        };
+        let key = scalar(key);
+        let value = scalar(value);
+        if value.is_empty() {
+            keys.push((indent, key));
+        } else if key == "name" {
+            let path: Vec<_> = keys.iter().map(|(_, key)| *key).collect();
+            if let Some(profile) = rule_list_scope(&path) {
+                names.push(RuleName {
+                    line: index + 1,
+                    profile,
+                    name: value.to_string(),
+                });
+            }
+        }
+    }
+
+    names
+}
+
+fn toml_rule_names(contents: &str) -> Vec<RuleName> {
+    let mut names = vec![];
+    let mut scope = None;
+    for (index, line) in contents.lines().enumerate() {
+        let trimmed = line.trim();
+        if let Some(header) = trimmed.strip_prefix("[[") {
+            let header = header.split("]]").next().unwrap_or_default();
+            let path: Vec<_> = header.split('.').map(scalar).collect();
+            scope = rule_list_scope(&path);
+            continue;
+        } else if trimmed.starts_with('[') {
+            scope = None;
+            continue;
+        }
+
+        let Some(profile) = &scope else {
+            continue;
+        };
+        let Some((key, value)) = trimmed.split_once('=') else {
+            continue;
+        };
+        if scalar(key) == "name" {
+            names.push(RuleName {
+                line: index + 1,
+                profile: profile.clone(),
+                name: scalar(value).to_string(),
+            });
+        }
+    }
+
+    names
+}
+
+fn json_rule_names(contents: &str) -> Vec<RuleName> {
+    enum Frame {
+        Object { key: Option<String>, in_key: bool },
+        Array,
+    }
+
+    let mut names = vec![];
+    let mut stack = vec![];
+    let mut line = 1;
+    let mut chars = contents.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => line += 1,
+            '{' => stack.push(Frame::Object {
+                key: None,
+                in_key: true,
+            }),
+            '[' => stack.push(Frame::Array),
+            '}' | ']' => {
+                stack.pop();
+            }
+            ',' | ':' => {
+                if let Some(Frame::Object { in_key, .. }) = stack.last_mut() {
+                    *in_key = c == ',';
+                }
+            }
+            '"' => {
+                let string_line = line;
+                let mut string = String::new();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => string.extend(chars.next()),
+                        '"' => break,
+                        c => {
+                            if c == '\n' {
+                                line += 1;
+                            }
+                            string.push(c);
+                        }
+                    }
+                }
+
+                match stack.last_mut() {
+                    Some(Frame::Object { key, in_key: true }) => *key = Some(string),
+                    Some(Frame::Object { key: Some(key), .. }) if key == "name" => {
+                        let path: Vec<_> = stack[..stack.len() - 1]
+                            .iter()
+                            .filter_map(|frame| match frame {
+                                Frame::Object { key: Some(key), .. } => Some(key.as_str()),
+                                _ => None,
+                            })
+                            .collect();
+                        if let Some(profile) = rule_list_scope(&path) {
+                            names.push(RuleName {
+                                line: string_line,
+                                profile,
+                                name: string,
+                            });
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    names
+}
+
+/// Whether `size` is a tmpfs `size=` option, ie. `512`, `64m` or `50%`.
+/// Anything else could smuggle extra mount options in after a comma.
+fn is_tmpfs_size(size: &str) -> bool {
+    let digits = size
+        .strip_suffix(['k', 'K', 'm', 'M', 'g', 'G', '%'])
+        .unwrap_or(size);
+    !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule_name(line: usize, profile: Option<&str>, name: &str) -> RuleName {
+        RuleName {
+            line,
+            profile: profile.map(String::from),
+            name: name.into(),
+        }
+    }
+
+    #[test]
+    fn test_rule_names_are_scoped_to_their_list() {
+        let yaml = "rules:\n- name: aws-prod\n  target: ~/.aws\n  when:\n    env:\n      name: aws\n- name: 'aws' # the default\n  target: ~/.aws\nprofiles:\n  work:\n    rules:\n    - target: ~/.kube\n      name: aws\n";
+        let toml = "[[rules]]\nname = \"aws-prod\"\n[rules.when.env]\nname = \"aws\"\n[[rules]]\nname = \"aws\"\n[[profiles.work.rules]]\nname = \"aws\"\n";
+        let json = "{\n  \"rules\": [\n    {\"name\": \"aws-prod\", \"env\": {\"name\": \"aws\"}},\n    {\"name\": \"aws\"}\n  ],\n  \"profiles\": {\"work\": {\"rules\": [\n    {\"name\": \"aws\"}\n  ]}}\n}\n";
+
+        assert_eq!(
+            rule_names("boxxy.yaml", yaml),
+            vec![
+                rule_name(2, None, "aws-prod"),
+                rule_name(7, None, "aws"),
+                rule_name(13, Some("work"), "aws"),
+            ]
+        );
+        assert_eq!(
+            rule_names("boxxy.toml", toml),
+            vec![
+                rule_name(2, None, "aws-prod"),
+                rule_name(6, None, "aws"),
+                rule_name(8, Some("work"), "aws"),
+            ]
+        );
+        assert_eq!(
+            rule_names("boxxy.json", json),
+            vec![
+                rule_name(3, None, "aws-prod"),
+                rule_name(4, None, "aws"),
+                rule_name(7, Some("work"), "aws"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rule_line_skips_lines_with_several_rules() {
+        let json = r#"{"rules": [{"name": "aws"}, {"name": "kube"}]}"#;
+        let validator = Validator::new("boxxy.json".into(), Some(json));
+
+        assert_eq!(validator.rule_line(None, "aws", 0), None);
+        assert_eq!(validator.rule_line(None, "kube", 0), None);
+    }
+
+    #[test]
+    fn test_tmpfs_sizes_cant_carry_mount_options() {
+        for size in ["512", "64m", "1G", "50%"] {
+            assert!(is_tmpfs_size(size), "{size}");
+        }
+        for size in ["", "m", "64mb", "-1", "64m,mode=777"] {
+            assert!(!is_tmpfs_size(size), "{size}");
+        }
+    }
+}
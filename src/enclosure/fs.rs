@@ -1,10 +1,12 @@
 use std::fs::{self, OpenOptions};
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 
 use color_eyre::Result;
 use eyre::eyre;
 use log::*;
 use nix::mount::{mount, MsFlags};
+use nix::sys::statvfs::{statvfs, FsFlags};
 
 pub struct FsDriver;
 
@@ -23,6 +25,12 @@ impl FsDriver {
         append_all(&self.all_containers_root(), vec![name])
     }
 
+    /// Scratch space for rules that need somewhere to put throwaway files,
+    /// ie file targets of `tmpfs` rules.
+    pub fn scratch_root(&self, name: &str) -> PathBuf {
+        append_all(&self.all_containers_root(), vec![format!("{name}-scratch")])
+    }
+
     pub fn setup_root(&self, name: &str) -> Result<()> {
         debug!("setting up root for {name}");
         fs::create_dir_all(self.container_root(name))?;
@@ -32,6 +40,10 @@ impl FsDriver {
     pub fn cleanup_root(&self, name: &str) -> Result<()> {
         debug!("cleaning up root for {name}");
         fs::remove_dir_all(self.container_root(name))?;
+        let scratch_root = self.scratch_root(name);
+        if scratch_root.exists() {
+            fs::remove_dir_all(scratch_root)?;
+        }
         Ok(())
     }
 
@@ -46,11 +58,35 @@ impl FsDriver {
 
     pub fn remount_ro(&self, target: &Path) -> Result<()> {
         debug!("remount {target:?} as ro");
+        self.remount(target, MsFlags::MS_RDONLY)
+    }
+
+    /// Remount `target` with the given flags added, ex. `MS_RDONLY` or
+    /// `MS_NOEXEC`. Flags already set on the mount are carried over, because
+    /// the kernel refuses to clear them from inside of a user namespace.
+    pub fn remount(&self, target: &Path, flags: MsFlags) -> Result<()> {
+        debug!("remount {target:?} with {flags:?}");
+        let current_flags = statvfs(target)?.flags();
+        let mut flags = flags;
+        for (fs_flag, ms_flag) in [
+            (FsFlags::ST_RDONLY, MsFlags::MS_RDONLY),
+            (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+            (FsFlags::ST_NODEV, MsFlags::MS_NODEV),
+            (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+            (FsFlags::ST_NOATIME, MsFlags::MS_NOATIME),
+            (FsFlags::ST_NODIRATIME, MsFlags::MS_NODIRATIME),
+            (FsFlags::ST_RELATIME, MsFlags::MS_RELATIME),
+        ] {
+            if current_flags.contains(fs_flag) {
+                flags |= ms_flag;
+            }
+        }
+
         mount::<Path, Path, str, str>(
             None,
             target,
             Some(""),
-            MsFlags::MS_REMOUNT | MsFlags::MS_BIND | MsFlags::MS_RDONLY,
+            MsFlags::MS_REMOUNT | MsFlags::MS_BIND | flags,
             Some(""),
         )?;
         Ok(())
@@ -61,6 +97,97 @@ impl FsDriver {
         self.bind_mount(src, target, MsFlags::MS_BIND)
     }
 
+    pub fn tmpfs_mount(&self, target: &Path, size: Option<&str>) -> Result<()> {
+        debug!("tmpfs mount onto {target:?} (size: {size:?})");
+        if !target.is_dir() {
+            return Err(eyre!("Cannot mount a tmpfs onto a file: {target:?}"));
+        }
+
+        let options = match size {
+            Some(size) => format!("size={size}"),
+            None => String::new(),
+        };
+        mount(
+            Some("tmpfs"),
+            target,
+            Some("tmpfs"),
+            MsFlags::empty(),
+            Some(options.as_str()),
+        )?;
+        Ok(())
+    }
+
+    pub fn hide(&self, target: &Path) -> Result<()> {
+        if target.is_dir() {
+            debug!("hide {target:?} behind an empty ro tmpfs");
+            mount(
+                Some("tmpfs"),
+                target,
+                Some("tmpfs"),
+                MsFlags::MS_RDONLY | MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+                Some(""),
+            )?;
+            Ok(())
+        } else {
+            debug!("hide {target:?} behind /dev/null");
+            self.bind_mount_ro(Path::new("/dev/null"), target)
+        }
+    }
+
+    pub fn overlay_mount(&self, lower: &Path, upper: &Path, target: &Path) -> Result<()> {
+        let work = self.overlay_work_dir(upper);
+        debug!("overlay mount {lower:?} + {upper:?} onto {target:?} (work: {work:?})");
+
+        if !lower.is_dir() || !upper.is_dir() || !target.is_dir() {
+            return Err(eyre!(
+                "Cannot overlay mount anything but directories: {lower:?} + {upper:?} -> {target:?}"
+            ));
+        }
+        self.touch_dir(&work)?;
+
+        let options = format!(
+            "lowerdir={},upperdir={},workdir={}",
+            lower.display(),
+            upper.display(),
+            work.display()
+        );
+        mount(
+            Some("overlay"),
+            target,
+            Some("overlay"),
+            MsFlags::empty(),
+            Some(options.as_str()),
+        )?;
+        Ok(())
+    }
+
+    /// The work directory overlayfs needs for the given upper directory. It
+    /// has to live on the same filesystem as the upper directory, but can't
+    /// be inside of it, so it's kept as a hidden sibling.
+    pub fn overlay_work_dir(&self, upper: &Path) -> PathBuf {
+        let name = upper
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        upper.with_file_name(format!(".{name}.boxxy-work"))
+    }
+
+    /// Remove an overlay work directory. overlayfs leaves a `work`
+    /// directory with no permissions inside of it, so permissions are
+    /// restored before removing anything.
+    pub fn remove_overlay_work_dir(&self, work: &Path) -> Result<()> {
+        debug!("removing overlay work dir {work:?}");
+        fs::set_permissions(work, fs::Permissions::from_mode(0o700))?;
+        for entry in fs::read_dir(work)? {
+            let path = entry?.path();
+            if fs::symlink_metadata(&path)?.is_dir() {
+                fs::set_permissions(&path, fs::Permissions::from_mode(0o700))?;
+            }
+        }
+        fs::remove_dir_all(work)?;
+        Ok(())
+    }
+
     fn bind_mount(&self, src: &Path, target: &Path, flags: MsFlags) -> Result<()> {
         debug!("bind mount {src:?} onto {target:?}");
 
@@ -107,6 +234,51 @@ impl FsDriver {
         }
     }
 
+    /// Whether a path has no contents, ie it doesn't exist, or is an empty
+    /// file or directory.
+    pub fn is_empty(&self, path: &Path) -> Result<bool> {
+        if !path.exists() {
+            Ok(true)
+        } else if path.is_dir() {
+            Ok(fs::read_dir(path)?.next().is_none())
+        } else {
+            Ok(path.metadata()?.len() == 0)
+        }
+    }
+
+    pub fn copy_recursive(&self, src: &Path, target: &Path) -> Result<()> {
+        debug!("copying {src:?} to {target:?}");
+        let metadata = fs::symlink_metadata(src)?;
+        if metadata.is_symlink() {
+            std::os::unix::fs::symlink(fs::read_link(src)?, target)?;
+        } else if metadata.is_dir() {
+            self.touch_dir(target)?;
+            for entry in fs::read_dir(src)? {
+                let entry = entry?;
+                self.copy_recursive(&entry.path(), &target.join(entry.file_name()))?;
+            }
+        } else {
+            fs::copy(src, target)?;
+        }
+        Ok(())
+    }
+
+    pub fn move_path(&self, src: &Path, target: &Path) -> Result<()> {
+        debug!("moving {src:?} to {target:?}");
+        if fs::rename(src, target).is_ok() {
+            return Ok(());
+        }
+
+        // Renaming fails across filesystems, so fall back to copy + delete.
+        self.copy_recursive(src, target)?;
+        if src.is_dir() {
+            fs::remove_dir_all(src)?;
+        } else {
+            fs::remove_file(src)?;
+        }
+        Ok(())
+    }
+
     pub fn fully_expand_path(&self, path: &String) -> Result<PathBuf> {
         let expanded = shellexpand::tilde(&path).to_string();
         match Path::new(&expanded).canonicalize() {
@@ -219,4 +391,65 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_overlay_work_dir_is_hidden_sibling() {
+        let driver = FsDriver::new();
+        assert_eq!(
+            driver.overlay_work_dir(Path::new("/home/boxxy/.config/tool")),
+            PathBuf::from("/home/boxxy/.config/.tool.boxxy-work")
+        );
+    }
+
+    #[test]
+    fn test_fs_driver_removes_overlay_work_dirs() -> Result<()> {
+        let driver = FsDriver::new();
+        let name = "test-overlay-work-dir";
+        let root = driver.container_root(name);
+        driver.setup_root(name)?;
+        let work = driver.overlay_work_dir(&append_all(&root, vec!["upper"]));
+        driver.touch_dir(&append_all(&work, vec!["work"]))?;
+        fs::set_permissions(
+            append_all(&work, vec!["work"]),
+            fs::Permissions::from_mode(0o000),
+        )?;
+
+        let removed = driver.remove_overlay_work_dir(&work);
+        let exists = work.exists();
+        driver.cleanup_root(name)?;
+
+        removed?;
+        assert!(!exists);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fs_driver_copies_and_moves_recursively() -> Result<()> {
+        let driver = FsDriver::new();
+        let name = "test-copy-move";
+        let root = driver.container_root(name);
+        driver.setup_root(name)?;
+        let src = append_all(&root, vec!["src"]);
+        driver.touch_dir(&append_all(&src, vec!["nested"]))?;
+        fs::write(append_all(&src, vec!["nested", "config"]), "hello")?;
+
+        let copied = append_all(&root, vec!["copied"]);
+        driver.copy_recursive(&src, &copied)?;
+        let moved = append_all(&root, vec!["moved"]);
+        driver.move_path(&src, &moved)?;
+
+        let copied_contents = fs::read_to_string(append_all(&copied, vec!["nested", "config"]));
+        let moved_contents = fs::read_to_string(append_all(&moved, vec!["nested", "config"]));
+        let src_exists = src.exists();
+        let src_empty = driver.is_empty(&src)?;
+        driver.cleanup_root(name)?;
+
+        assert_eq!(copied_contents?, "hello");
+        assert_eq!(moved_contents?, "hello");
+        assert!(!src_exists);
+        assert!(src_empty);
+
+        Ok(())
+    }
 }
@@ -43,6 +43,8 @@ pub struct Enclosure {
     child_exit_status: i32,
     created_files: Vec<PathBuf>,
     created_directories: Vec<PathBuf>,
+    created_work_dirs: Vec<PathBuf>,
+    scratch_mounted: bool,
 }
 
 impl Enclosure {
@@ -54,6 +56,8 @@ impl Enclosure {
             child_exit_status: -1,
             created_files: vec![],
             created_directories: vec![],
+            created_work_dirs: vec![],
+            scratch_mounted: false,
         }
     }
 
@@ -62,7 +66,10 @@ impl Enclosure {
         let applicable_rules = &self
             .config
             .rules
-            .get_all_applicable_rules(self.config.command.get_program(), &self.fs)?;
+            .get_all_applicable_rules(&self.config.command, &self.fs)?;
+        // Expand glob targets and detect modes once, so that the temporary
+        // files and the container mounts are set up for the exact same paths.
+        let applicable_rules = &self.resolve_rules(applicable_rules)?;
         self.set_up_temporary_files(applicable_rules)?;
 
         // Set up the container: callback, stack, etc.
@@ -245,6 +252,15 @@ impl Enclosure {
         exit(self.child_exit_status);
     }
 
+    fn resolve_rules(&self, applicable_rules: &[Rule]) -> Result<Vec<Rule>> {
+        let mut resolved_rules = vec![];
+        for rule in applicable_rules {
+            resolved_rules.extend(rule.resolve(&self.fs)?);
+        }
+
+        Ok(resolved_rules)
+    }
+
     fn set_up_temporary_files(&mut self, applicable_rules: &[Rule]) -> Result<Vec<PathBuf>> {
         for rule in applicable_rules {
             debug!("processing path creation for rule '{}'", rule.name);
@@ -254,21 +270,49 @@ impl Enclosure {
 
             let rewrite_path = self.fs.fully_expand_path(&rule.rewrite)?;
 
+            // Seeding happens before any paths are created, so that a moved
+            // target is recreated below as a temporary path to mount over.
+            rule.seed_rewrite(&target_path, &rewrite_path, &self.fs)?;
+
             debug!("temp files: ensuring path: {target_path:?}");
             debug!("temp files: rewriting to: {rewrite_path:?}");
 
             match rule.mode {
+                RuleMode::Auto => unreachable!("auto rule modes are resolved before setup"),
                 RuleMode::File => {
                     self.ensure_file(&rewrite_path)?;
                     if self.ensure_file(&target_path)? {
                         self.created_files.push(target_path.clone());
                     }
                 }
-                RuleMode::Directory => {
+                RuleMode::Directory | RuleMode::Overlay => {
                     self.ensure_directory(&rewrite_path)?;
                     if self.ensure_directory(&target_path)? {
                         self.created_directories.push(target_path.clone());
                     }
+                    // The work directory is created here rather than when
+                    // mounting, so that it can be cleaned up afterwards.
+                    if rule.mode == RuleMode::Overlay {
+                        let work_dir = self.fs.overlay_work_dir(&rewrite_path);
+                        if self.ensure_directory(&work_dir)? {
+                            self.created_work_dirs.push(work_dir);
+                        }
+                    }
+                }
+                RuleMode::Tmpfs if rule.file => {
+                    // File targets are shadowed from the scratch tmpfs, but
+                    // still need to exist to be mounted over.
+                    if self.ensure_file(&target_path)? {
+                        self.created_files.push(target_path.clone());
+                    }
+                }
+                RuleMode::Tmpfs => {
+                    if self.ensure_directory(&target_path)? {
+                        self.created_directories.push(target_path.clone());
+                    }
+                }
+                RuleMode::Hide => {
+                    // Nothing to create, a missing target is already hidden.
                 }
             }
 
@@ -297,14 +341,26 @@ impl Enclosure {
 
         // Load env vars from applicable rules
         for rule in applicable_rules {
-            for (key, value) in rule.env.iter() {
-                self.config.command.env(key, value);
-                debug!("loaded env var: {key}=********");
+            let changes =
+                rule.env_changes(self.config.command.get_program(), &self.fs, &|key| {
+                    self.current_env(key)
+                })?;
+            for (key, value) in &changes {
+                match value {
+                    Some(value) => {
+                        self.config.command.env(key, value);
+                        debug!("loaded env var: {key}=********");
+                    }
+                    None => {
+                        self.config.command.env_remove(key);
+                        debug!("removed env var: {key}");
+                    }
+                }
             }
-            if !rule.env.is_empty() {
+            if !changes.is_empty() {
                 debug!(
                     "loaded {} env vars from rule '{}'",
-                    rule.env.len(),
+                    changes.len(),
                     rule.name
                 );
             }
@@ -341,6 +397,7 @@ impl Enclosure {
 
             // If the target file doesn't exist, we have to create it in order to bind mount over it.
             match rule.mode {
+                RuleMode::Auto => unreachable!("auto rule modes are resolved before setup"),
                 RuleMode::File => {
                     if !target_path.exists() {
                         debug!("creating file: {target_path:?}");
@@ -357,6 +414,48 @@ impl Enclosure {
                     }
                     self.fs.bind_mount_rw(&rewrite_path, &target_path)?;
                 }
+                RuleMode::Overlay => {
+                    if !target_path.exists() {
+                        debug!("creating directory: {target_path:?}");
+                        self.ensure_directory(&target_path)?;
+                        self.created_files.push(target_path.clone());
+                    }
+                    // The original target outside of the container root is
+                    // the lower layer, so the program still sees its contents.
+                    let lower_path = self.fs.maybe_resolve_symlink(&expanded_target)?;
+                    self.fs
+                        .overlay_mount(&lower_path, &rewrite_path, &target_path)?;
+                }
+                RuleMode::Tmpfs => {
+                    if rule.file {
+                        if !target_path.exists() {
+                            debug!("creating file: {target_path:?}");
+                            self.ensure_file(&target_path)?;
+                            self.created_files.push(target_path.clone());
+                        }
+                        let scratch_file = self.ensure_scratch_file(&target_path)?;
+                        self.fs.bind_mount_rw(&scratch_file, &target_path)?;
+                    } else {
+                        if !target_path.exists() {
+                            debug!("creating directory: {target_path:?}");
+                            self.ensure_directory(&target_path)?;
+                            self.created_files.push(target_path.clone());
+                        }
+                        self.fs.tmpfs_mount(&target_path, rule.size.as_deref())?;
+                    }
+                }
+                RuleMode::Hide => {
+                    if target_path.exists() {
+                        self.fs.hide(&target_path)?;
+                    } else {
+                        debug!("rule apply: nothing to hide at {target_path:?}");
+                    }
+                }
+            }
+
+            if !rule.options.is_empty() && rule.mode != RuleMode::Hide {
+                debug!("rule apply: applying mount options {:?}", rule.options);
+                self.fs.remount(&target_path, rule.mount_flags())?;
             }
 
             debug!("rule apply: rewrote base path {rewrite_path:?} => {target_path:?}");
@@ -370,7 +469,9 @@ impl Enclosure {
             "{}",
             format!(
                 "cleaning up {} path(s) ♥",
-                self.created_directories.len() + self.created_files.len()
+                self.created_directories.len()
+                    + self.created_files.len()
+                    + self.created_work_dirs.len()
             )
             .if_supports_color(owo_colors::Stream::Stdout, |text| text.fg::<PinkSalmon>())
         );
@@ -378,6 +479,9 @@ impl Enclosure {
             debug!("removing temporary file {}", file.display());
             std::fs::remove_file(file)?;
         }
+        for dir in &self.created_work_dirs {
+            self.fs.remove_overlay_work_dir(dir)?;
+        }
         for dir in self.created_directories.iter().rev() {
             debug!("removing temporary directory {}", dir.display());
             std::fs::remove_dir(dir)?;
@@ -544,6 +648,36 @@ impl Enclosure {
         Ok(child_exit_status.try_into()?)
     }
 
+    /// The value an env var will have in the command, taking into account
+    /// the env vars that boxxy has set or removed so far.
+    fn current_env(&self, key: &str) -> Option<String> {
+        match self
+            .config
+            .command
+            .get_envs()
+            .find(|(current_key, _)| *current_key == key)
+        {
+            Some((_, value)) => value.map(|value| value.to_string_lossy().to_string()),
+            None => std::env::var(key).ok(),
+        }
+    }
+
+    /// Create an empty file on the scratch tmpfs to shadow the given file
+    /// with, mounting the scratch tmpfs first if needed.
+    fn ensure_scratch_file(&mut self, target: &Path) -> Result<PathBuf> {
+        let scratch_root = self.fs.scratch_root(&self.name);
+        if !self.scratch_mounted {
+            debug!("mounting scratch tmpfs at {scratch_root:?}");
+            self.fs.touch_dir(&scratch_root)?;
+            self.fs.tmpfs_mount(&scratch_root, None)?;
+            self.scratch_mounted = true;
+        }
+
+        let scratch_file = append_all(&scratch_root, vec![target]);
+        self.ensure_file(&scratch_file)?;
+        Ok(scratch_file)
+    }
+
     fn ensure_file(&self, path: &Path) -> Result<bool> {
         if !path.exists() {
             if let Some(parent) = path.parent() {
@@ -567,3 +701,60 @@ impl Enclosure {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::process::Command;
+
+    use crate::enclosure::rule::{BoxxyRules, SeedMode};
+
+    fn enclosure() -> Enclosure {
+        Enclosure::new(BoxxyConfig {
+            rules: BoxxyRules::default(),
+            immutable_root: false,
+            trace: false,
+            dotenv: false,
+            daemon: false,
+            command: Command::new("true"),
+        })
+    }
+
+    #[test]
+    fn test_moved_target_is_recreated_as_a_placeholder() -> Result<()> {
+        let fs = FsDriver::new();
+        let name = "test-seed-placeholder";
+        let root = fs.container_root(name);
+        fs.setup_root(name)?;
+        let target = root.join("history");
+        let rewrite = root.join("rewrite").join("history");
+        std::fs::write(&target, "old history")?;
+        let rule = Rule {
+            name: "history".into(),
+            target: target.to_string_lossy().to_string(),
+            rewrite: rewrite.to_string_lossy().to_string(),
+            mode: RuleMode::File,
+            seed: Some(SeedMode::Move),
+            ..Default::default()
+        };
+
+        let mut enclosure = enclosure();
+        let set_up = enclosure.set_up_temporary_files(&[rule]);
+        let seeded = std::fs::read_to_string(&rewrite).ok();
+        let placeholder = std::fs::read_to_string(&target).ok();
+        let created_files = enclosure.created_files.clone();
+        let cleaned_up = enclosure.clean_up_container();
+        let target_exists = target.exists();
+        fs.cleanup_root(name)?;
+
+        set_up?;
+        cleaned_up?;
+        assert_eq!(seeded.as_deref(), Some("old history"));
+        assert_eq!(placeholder.as_deref(), Some(""));
+        assert_eq!(created_files, vec![target]);
+        assert!(!target_exists);
+
+        Ok(())
+    }
+}
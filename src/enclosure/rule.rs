@@ -1,81 +1,959 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::env::VarError;
 use std::ffi::OsStr;
 use std::path::{Path, PathBuf};
+use std::process::Command;
 use std::str::FromStr;
 
 use color_eyre::Result;
+use dotenv_parser::parse_dotenv;
+use eyre::eyre;
 use log::*;
-use serde::{Deserialize, Serialize};
+use nix::mount::MsFlags;
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer, Serialize};
 
-use super::fs::FsDriver;
+use super::fs::{append_all, FsDriver};
 
 /// Container for deserialisation
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct BoxxyRules {
+    /// Rules that always apply, regardless of the active profile.
+    #[serde(default)]
     pub rules: Vec<Rule>,
+    /// Named groups of rules and settings, selected with `--profile` or
+    /// `$BOXXY_PROFILE`.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub profiles: HashMap<String, Profile>,
+    /// Other config files to load rules from, as paths or globs. Relative
+    /// paths are resolved relative to the including config file.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
 }
 
 impl BoxxyRules {
-    pub fn get_all_applicable_rules(&self, binary: &OsStr, fs: &FsDriver) -> Result<Vec<Rule>> {
+    /// Activate the named profile, layering its rules on top of the
+    /// always-on rules. Returns the rest of the profile if it's defined here.
+    pub fn activate_profile(&mut self, name: &str) -> Option<Profile> {
+        let mut profile = self.profiles.remove(name)?;
+        self.rules.append(&mut profile.rules);
+        Some(profile)
+    }
+
+    pub fn get_all_applicable_rules(&self, command: &Command, fs: &FsDriver) -> Result<Vec<Rule>> {
         let mut applicable_rules = vec![];
+        for check in self.check_rules(command, fs)? {
+            if let Some(rule) = check.applied? {
+                applicable_rules.push(rule);
+            }
+        }
+
+        Self::drop_shadowed_targets(applicable_rules, fs)
+    }
+
+    /// Check every rule against a command, recording why each one does or
+    /// doesn't apply. Shadowed targets are not dropped. A rule that can't be
+    /// checked, ie because of an invalid regex, doesn't apply, and the error
+    /// is recorded as its last reason.
+    pub fn explain(&self, command: &Command, fs: &FsDriver) -> Result<Vec<RuleExplanation>> {
+        let explanations = self
+            .check_rules(command, fs)?
+            .into_iter()
+            .map(|mut check| {
+                let applied = check.applied.unwrap_or_else(|e| {
+                    check.reasons.push(format!("error: {e}"));
+                    None
+                });
+                RuleExplanation {
+                    rule: check.rule.clone(),
+                    applied,
+                    reasons: check.reasons,
+                }
+            })
+            .collect();
+
+        Ok(explanations)
+    }
+
+    /// Check every rule against a command, keeping each rule's result
+    /// separate so that one broken rule doesn't hide the others.
+    fn check_rules(&self, command: &Command, fs: &FsDriver) -> Result<Vec<RuleCheck<'_>>> {
+        let binary = command.get_program();
+        let args: Vec<String> = command
+            .get_args()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect();
+        let pwd = std::env::current_dir()?;
+
+        Ok(self
+            .rules
+            .iter()
+            .map(|rule| {
+                let mut reasons = vec![];
+                let applied = rule.explain(binary, &args, &pwd, fs, &mut reasons);
+                RuleCheck {
+                    rule,
+                    applied,
+                    reasons,
+                }
+            })
+            .collect())
+    }
+
+    /// All rules that apply in the current directory and environment,
+    /// regardless of which program is being run. `$COMMAND` expands to an
+    /// empty string.
+    pub fn get_rules_in_context(&self, fs: &FsDriver) -> Result<Vec<Rule>> {
+        let mut rules = vec![];
 
         for rule in &self.rules {
-            debug!("{}: checking if rule applies to binary", rule.name);
-            if rule.currently_in_context(fs)? && rule.applies_to_binary(binary, fs)? {
-                debug!("{}: rule applies to binary via only + context!", rule.name);
-                applicable_rules.push(rule.clone());
-            } else if rule.applies_to_binary(binary, fs)? {
-                debug!(
-                    "{}: rule applies to binary via only but NOT context!",
-                    rule.name
+            if !rule.when.is_met(&rule.name) {
+                debug!("{}: rule conditions not met, skipping!", rule.name);
+                continue;
+            }
+            if let Some(rule) = rule.expand_in_context(OsStr::new(""), fs)? {
+                rules.push(rule);
+            }
+        }
+
+        Self::drop_shadowed_targets(rules, fs)
+    }
+
+    /// Drop rules whose target is also claimed by a later rule. Rules are
+    /// merged in order of increasing precedence, so the last rule wins.
+    pub fn drop_shadowed_targets(rules: Vec<Rule>, fs: &FsDriver) -> Result<Vec<Rule>> {
+        let mut kept_rules: Vec<Rule> = vec![];
+        let mut kept_targets: Vec<PathBuf> = vec![];
+
+        for rule in rules {
+            let target = fs.fully_expand_path(&rule.target)?;
+            if let Some(index) = kept_targets.iter().position(|t| *t == target) {
+                warn!(
+                    "rule '{}' shadows rule '{}' for target {}",
+                    rule.name,
+                    kept_rules[index].name,
+                    target.display()
                 );
-                applicable_rules.push(rule.clone());
+                kept_rules.remove(index);
+                kept_targets.remove(index);
             }
+            kept_rules.push(rule);
+            kept_targets.push(target);
         }
 
-        Ok(applicable_rules)
+        Ok(kept_rules)
+    }
+}
+
+/// The result of checking a single rule against a command.
+struct RuleCheck<'a> {
+    rule: &'a Rule,
+    applied: Result<Option<Rule>>,
+    reasons: Vec<String>,
+}
+
+/// Whether a rule applies to a command, and why.
+#[derive(Debug, Clone)]
+pub struct RuleExplanation {
+    /// The rule as it was loaded.
+    pub rule: Rule,
+    /// The rule with its variables expanded, if it applies.
+    pub applied: Option<Rule>,
+    /// Every check made against the rule, in order. If the rule doesn't
+    /// apply, the last one is why.
+    pub reasons: Vec<String>,
+}
+
+/// How a binary matched an `only` or `except` entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::Display)]
+pub enum ProgramMatch {
+    #[strum(serialize = "regex")]
+    Regex,
+    #[strum(serialize = "glob")]
+    Glob,
+    #[strum(serialize = "file name")]
+    FileName,
+    #[strum(serialize = "path")]
+    Path,
+    #[strum(serialize = "canonical path")]
+    CanonicalPath,
+    #[strum(serialize = "resolved symlink")]
+    Symlink,
+    #[strum(serialize = "$PATH lookup")]
+    Which,
+}
+
+/// Whether a rule's `only` and `except` entries allow a binary.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BinaryMatch {
+    /// The rule has no `only` entries, and no `except` entry matched.
+    Any,
+    /// The binary matched this `only` entry.
+    Only(String, ProgramMatch),
+    /// The binary matched this `except` entry.
+    Excluded(String, ProgramMatch),
+    /// The rule has `only` entries, and none of them matched.
+    NotListed,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    /// Rules that only apply when this profile is active.
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    /// Environment variables injected into every command run with this
+    /// profile.
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// Make the root filesystem immutable, as with `--immutable`.
+    #[serde(default)]
+    pub immutable: bool,
+    /// Load the .env file in the current directory, as with `--dotenv`.
+    #[serde(default)]
+    pub dotenv: bool,
+}
+
+impl Profile {
+    /// Combine another definition of the same profile into this one, ie
+    /// when a profile is spread over several config files.
+    pub fn extend(&mut self, other: Profile) {
+        self.rules.extend(other.rules);
+        self.env.extend(other.env);
+        self.immutable |= other.immutable;
+        self.dotenv |= other.dotenv;
     }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct Rule {
     /// The name of this rule
     pub name: String,
     /// The target directory/file of this rule, ie the path that will be
-    /// shadowed.
+    /// shadowed. May be a glob pattern, ex. `~/.pine-debug[1-4]`, in which
+    /// case every match is shadowed by a path of the same name inside the
+    /// rewrite.
     pub target: String,
-    /// The path to shadow the target with.
+    /// The path to shadow the target with. Not used by `tmpfs` and `hide`
+    /// rules.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub rewrite: String,
-    /// The mode of the rule, ie whether the target is a file or a directory.
+    /// The mode of the rule, ie whether the target is a file or a directory,
+    /// or whether the rewrite is layered over the target as an overlay.
+    /// Defaults to `auto`, which detects files and directories from the
+    /// paths on disk.
     #[serde(default = "default_rule_mode")]
     pub mode: RuleMode,
+    /// The maximum size of the tmpfs mounted by `tmpfs` rules, ex. `64m`. If
+    /// this is not specified, the kernel default of half of the RAM is used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<String>,
+    /// Whether the target of a `tmpfs` rule is a file, so that a missing
+    /// target is created as an empty file rather than a directory, ex.
+    /// `~/.python_history`. Existing targets are detected automatically.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub file: bool,
+    /// Extra options to mount the rule with, ex. `ro` to give the program a
+    /// read-only view of the rewrite. Not used by `hide` rules.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub options: Vec<MountOption>,
     /// The context of the rule, ie the full path to the directories where this rule applies.
-    #[serde(default = "empty_vec")]
+    /// Entries may also be globs, `git:<remote url glob>`, or `marker:<file name>`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub context: Vec<String>,
+    /// The contexts where this rule never applies, even if it matches
+    /// `context`. Entries use the same syntax as `context`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub not_context: Vec<String>,
     /// The binaries that this rule applies to. If this is not specified, or if
-    /// this is an empty list, then the rule applies to all binaries.
-    #[serde(default = "empty_vec")]
+    /// this is an empty list, then the rule applies to all binaries. Entries
+    /// may be names, paths, globs (`kubectl-*`), or regexes prefixed with
+    /// `regex:` (`regex:^python3\.\d+$`).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub only: Vec<String>,
+    /// The binaries that this rule never applies to, even if they match
+    /// `only`. Entries use the same syntax as `only`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub except: Vec<String>,
+    /// Argument patterns that the command's arguments must match for this
+    /// rule to apply. Each entry is a glob matching one argument, and `**` or
+    /// a trailing `*` match any number of arguments, ex. `["commit", "*"]`
+    /// only applies to `git commit`. If this is empty, the rule applies to
+    /// any arguments.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
     /// Environment variables that this rule applies if it matches. Any env
     /// vars listed here will be injected into the environment of the command
-    /// that is being boxxed.
-    #[serde(default = "empty_hashmap")]
+    /// that is being boxxed. Instead of a value, an env var can also be given
+    /// `prepend`/`append` to add to its current value, or `unset: true` to
+    /// remove it.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub env: HashMap<String, EnvValue>,
+    /// Dotenv files to load env vars from if this rule matches. Env vars set
+    /// in `env` take precedence over ones loaded from these files.
+    #[serde(
+        default,
+        deserialize_with = "one_or_many",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    #[schemars(with = "OneOrMany")]
+    pub env_file: Vec<String>,
+    /// Conditions on the environment boxxy is run in that must hold for this
+    /// rule to apply.
+    #[serde(default, skip_serializing_if = "RuleCondition::is_empty")]
+    pub when: RuleCondition,
+    /// What to do when the target or rewrite doesn't exist: `create` it,
+    /// `skip` the rule, or `error`. Either one action for both, or a map with
+    /// separate `target` and `rewrite` actions. Defaults to `create`.
+    #[serde(default, skip_serializing_if = "OnMissing::is_default")]
+    pub on_missing: OnMissing,
+    /// Seed an empty rewrite from the original target the first time the
+    /// rule is applied, by either `copy`ing or `move`ing the target's
+    /// contents. Only used by `file` and `directory` rules.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<SeedMode>,
+    /// The config file this rule was loaded from, if any.
+    #[serde(skip)]
+    pub source: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, JsonSchema, Serialize)]
+#[serde(untagged)]
+pub enum EnvValue {
+    /// Set the env var to this value. Numbers and booleans are turned into
+    /// strings, ex. `DEBUG: 1`.
+    Set(
+        #[serde(deserialize_with = "scalar_to_string")]
+        #[schemars(with = "Scalar")]
+        String,
+    ),
+    /// Change the current value of the env var.
+    Modify(EnvModification),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, JsonSchema, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct EnvModification {
+    /// Add this to the start of the current value, separated by `:`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prepend: Option<String>,
+    /// Add this to the end of the current value, separated by `:`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub append: Option<String>,
+    /// Remove the env var entirely.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub unset: bool,
+}
+
+impl EnvValue {
+    /// The new value of an env var, given its current value. `None` means
+    /// that the env var should be removed.
+    pub fn apply(&self, current: Option<&str>) -> Option<String> {
+        let modification = match self {
+            EnvValue::Set(value) => return Some(value.clone()),
+            EnvValue::Modify(modification) => modification,
+        };
+        if modification.unset {
+            return None;
+        }
+
+        let current = current.filter(|current| !current.is_empty());
+        let parts: Vec<&str> = [
+            modification.prepend.as_deref(),
+            current,
+            modification.append.as_deref(),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+
+        if parts.is_empty() {
+            None
+        } else {
+            Some(parts.join(":"))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleCondition {
+    /// Env vars that must be set to exactly the given values, ex. `CI: "true"`.
+    #[serde(default)]
     pub env: HashMap<String, String>,
+    /// Env vars that must be set, to any value.
+    #[serde(default)]
+    pub set: Vec<String>,
+    /// Env vars that must not be set.
+    #[serde(default)]
+    pub unset: Vec<String>,
+}
+
+impl RuleCondition {
+    pub fn is_empty(&self) -> bool {
+        self.env.is_empty() && self.set.is_empty() && self.unset.is_empty()
+    }
+
+    pub fn is_met(&self, rule_name: &str) -> bool {
+        self.unmet(rule_name).is_none()
+    }
+
+    /// The first condition that doesn't hold, if any.
+    pub fn unmet(&self, rule_name: &str) -> Option<String> {
+        for (key, expected) in &self.env {
+            let value = std::env::var(key).ok();
+            debug!("{rule_name}: checking env condition: {key}={value:?}, expected={expected:?}");
+            if value.as_ref() != Some(expected) {
+                return Some(match value {
+                    Some(value) => format!("{key}={expected:?}, but it's {value:?}"),
+                    None => format!("{key}={expected:?}, but it's unset"),
+                });
+            }
+        }
+
+        for key in &self.set {
+            debug!("{rule_name}: checking that env var is set: {key}");
+            if std::env::var_os(key).is_none() {
+                return Some(format!("{key} must be set"));
+            }
+        }
+
+        for key in &self.unset {
+            debug!("{rule_name}: checking that env var is unset: {key}");
+            if std::env::var_os(key).is_some() {
+                return Some(format!("{key} must be unset"));
+            }
+        }
+
+        None
+    }
 }
 
 impl Rule {
+    /// Expand `~`, `$VAR` and `${VAR:-default}` in the target, rewrite,
+    /// contexts and env files of this rule. On top of the environment,
+    /// `$PROJECT_ROOT` is the directory of the config file the rule came
+    /// from, `$COMMAND` is the name of the boxxed binary, and the XDG base
+    /// directories always have a value. Env values are left alone until the
+    /// env is applied, see [`Rule::expand_env`].
+    pub fn expand_variables(&self, binary: &OsStr) -> Result<Rule> {
+        let process_env = |var: &str| std::env::var(var).ok();
+
+        let mut rule = self.clone();
+        self.expand_value(&mut rule.target, binary, &process_env)?;
+        self.expand_value(&mut rule.rewrite, binary, &process_env)?;
+        for value in rule
+            .context
+            .iter_mut()
+            .chain(rule.not_context.iter_mut())
+            .chain(rule.env_file.iter_mut())
+        {
+            self.expand_value(value, binary, &process_env)?;
+        }
+
+        Ok(rule)
+    }
+
+    /// Expand the variables in this rule's env values like
+    /// [`Rule::expand_variables`] does, but looking `$VAR` up in `current_env`,
+    /// ie the env the command has so far. That way `PATH: "/opt/bin:$PATH"`
+    /// keeps whatever the active profile, env files or earlier rules did to
+    /// `PATH`.
+    pub fn expand_env(
+        &self,
+        binary: &OsStr,
+        current_env: &dyn Fn(&str) -> Option<String>,
+    ) -> Result<HashMap<String, EnvValue>> {
+        let mut env = self.env.clone();
+        for env_value in env.values_mut() {
+            match env_value {
+                EnvValue::Set(value) => self.expand_value(value, binary, current_env)?,
+                EnvValue::Modify(modification) => {
+                    for value in modification
+                        .prepend
+                        .iter_mut()
+                        .chain(modification.append.iter_mut())
+                    {
+                        self.expand_value(value, binary, current_env)?;
+                    }
+                }
+            }
+        }
+
+        Ok(env)
+    }
+
+    /// Load the env vars from this rule's `env_file`s, with later files
+    /// overriding earlier ones.
+    pub fn load_env_files(&self, fs: &FsDriver) -> Result<BTreeMap<String, String>> {
+        let mut vars = BTreeMap::new();
+        for env_file in &self.env_file {
+            let env_file = fs.fully_expand_path(env_file)?;
+            debug!("{}: loading env vars from {env_file:?}", self.name);
+            let contents = std::fs::read_to_string(&env_file).map_err(|err| {
+                eyre!("{}: could not read env file {env_file:?}: {err}", self.name)
+            })?;
+            let dotenv = parse_dotenv(&contents).map_err(|err| {
+                eyre!(
+                    "{}: could not parse env file {env_file:?}: {err}",
+                    self.name
+                )
+            })?;
+            vars.extend(dotenv);
+        }
+
+        Ok(vars)
+    }
+
+    /// The changes this rule makes to a command's env, given the env the
+    /// command has so far: the vars from `env_file`s, with `env` applied on
+    /// top of them. `None` removes an env var.
+    pub fn env_changes(
+        &self,
+        binary: &OsStr,
+        fs: &FsDriver,
+        current_env: &dyn Fn(&str) -> Option<String>,
+    ) -> Result<BTreeMap<String, Option<String>>> {
+        let mut changes: BTreeMap<String, Option<String>> = self
+            .load_env_files(fs)?
+            .into_iter()
+            .map(|(key, value)| (key, Some(value)))
+            .collect();
+        let lookup = |key: &str| match changes.get(key) {
+            Some(value) => value.clone(),
+            None => current_env(key),
+        };
+
+        let env = match self.expand_env(binary, &lookup) {
+            Ok(env) => env,
+            Err(e) => {
+                warn!("{e}, skipping the rule's env vars");
+                HashMap::new()
+            }
+        };
+        let updates: Vec<_> = env
+            .iter()
+            .map(|(key, value)| (key.clone(), value.apply(lookup(key).as_deref())))
+            .collect();
+        changes.extend(updates);
+
+        Ok(changes)
+    }
+
+    fn expand_value(
+        &self,
+        value: &mut String,
+        binary: &OsStr,
+        env: &dyn Fn(&str) -> Option<String>,
+    ) -> Result<()> {
+        let project_root = match self.source.as_ref().and_then(|source| source.parent()) {
+            Some(project_root) => project_root.to_path_buf(),
+            None => std::env::current_dir()?,
+        };
+        let project_root = project_root.to_string_lossy().to_string();
+        let command = Path::new(binary)
+            .file_name()
+            .unwrap_or(binary)
+            .to_string_lossy()
+            .to_string();
+
+        let lookup = |var: &str| -> Result<Option<String>, VarError> {
+            match var {
+                "PROJECT_ROOT" => Ok(Some(project_root.clone())),
+                "COMMAND" => Ok(Some(command.clone())),
+                _ => match env(var)
+                    .or_else(|| default_xdg_dir(var).map(|dir| dir.to_string_lossy().to_string()))
+                {
+                    Some(value) => Ok(Some(value)),
+                    None => Err(VarError::NotPresent),
+                },
+            }
+        };
+        let home_dir = || dirs::home_dir().map(|home| home.to_string_lossy().to_string());
+        match shellexpand::full_with_context(value.as_str(), home_dir, lookup) {
+            Ok(expanded) => {
+                *value = expanded.to_string();
+                Ok(())
+            }
+            Err(err) => Err(eyre!("{}: could not expand {value:?}: {err}", self.name)),
+        }
+    }
+
+    /// Whether the target of this rule is a glob pattern that has to be
+    /// expanded before it can be mounted.
+    pub fn has_glob_target(&self) -> bool {
+        self.target.contains(['*', '?', '['])
+    }
+
+    /// Expand a glob target into one rule per matching path. Each match is
+    /// rewritten to the path of the same file name inside the rewrite. Rules
+    /// without a glob target are returned unchanged.
+    pub fn expand_target(&self, fs: &FsDriver) -> Result<Vec<Rule>> {
+        if !self.has_glob_target() {
+            return Ok(vec![self.clone()]);
+        }
+
+        let pattern = shellexpand::tilde(&self.target).to_string();
+        let rewrite_root = fs.fully_expand_path(&self.rewrite)?;
+        debug!("{}: expanding glob target: {pattern}", self.name);
+
+        let mut expanded = vec![];
+        for path in glob::glob(&pattern)? {
+            let path = path?;
+            let Some(file_name) = path.file_name() else {
+                continue;
+            };
+            let rewrite = append_all(&rewrite_root, vec![file_name]);
+            debug!("{}: glob matched {path:?} => {rewrite:?}", self.name);
+
+            expanded.push(Rule {
+                target: path.to_string_lossy().to_string(),
+                rewrite: rewrite.to_string_lossy().to_string(),
+                ..self.clone()
+            });
+        }
+
+        if expanded.is_empty() {
+            debug!("{}: glob target matched nothing, skipping!", self.name);
+        }
+
+        Ok(expanded)
+    }
+
+    /// Expand a glob target, apply `on_missing` and resolve `auto` modes,
+    /// giving the exact rules to mount. Rules skipped because of a missing
+    /// path are left out.
+    pub fn resolve(&self, fs: &FsDriver) -> Result<Vec<Rule>> {
+        let mut resolved_rules = vec![];
+        for mut rule in self.expand_target(fs)? {
+            if !rule.check_missing_paths(fs)? {
+                continue;
+            }
+            rule.mode = rule.resolved_mode(fs)?;
+            if rule.mode == RuleMode::Tmpfs && !rule.file {
+                rule.file = fs.fully_expand_path(&rule.target)?.is_file();
+            }
+            resolved_rules.push(rule);
+        }
+
+        Ok(resolved_rules)
+    }
+
+    /// Check the target and rewrite of this rule against `on_missing`.
+    /// Returns whether the rule should be applied, or an error if a path the
+    /// rule requires is missing.
+    pub fn check_missing_paths(&self, fs: &FsDriver) -> Result<bool> {
+        let target_path = fs.fully_expand_path(&self.target)?;
+        let rewrite_path = fs.fully_expand_path(&self.rewrite)?;
+        // A missing rewrite that's about to be seeded from the target isn't
+        // missing for long.
+        let seeded = self.seed_mode(&target_path, &rewrite_path, fs)?.is_some();
+
+        let mut paths = vec![("target", target_path, self.on_missing.target)];
+        if !matches!(self.mode, RuleMode::Tmpfs | RuleMode::Hide) && !seeded {
+            paths.push(("rewrite", rewrite_path, self.on_missing.rewrite));
+        }
+
+        for (kind, path, action) in paths {
+            if path.exists() {
+                continue;
+            }
+            match action {
+                MissingAction::Create => {}
+                MissingAction::Skip => {
+                    info!(
+                        "skipping rule '{}' because its {kind} {} doesn't exist",
+                        self.name,
+                        path.display()
+                    );
+                    return Ok(false);
+                }
+                MissingAction::Error => {
+                    return Err(eyre!(
+                        "rule '{}': {kind} {} doesn't exist! (on_missing: error)",
+                        self.name,
+                        path.display()
+                    ));
+                }
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// How the rewrite should be seeded from the target, if at all. Only
+    /// `file` and `directory` rules are seeded, and only when the target has
+    /// contents and the rewrite is still empty.
+    pub fn seed_mode(
+        &self,
+        target_path: &Path,
+        rewrite_path: &Path,
+        fs: &FsDriver,
+    ) -> Result<Option<SeedMode>> {
+        let Some(seed) = self.seed else {
+            return Ok(None);
+        };
+        if !matches!(
+            self.mode,
+            RuleMode::Auto | RuleMode::File | RuleMode::Directory
+        ) {
+            debug!("{}: {} rules can't be seeded", self.name, self.mode);
+            return Ok(None);
+        }
+        if fs.is_empty(target_path)? || !fs.is_empty(rewrite_path)? {
+            debug!("{}: nothing to seed {rewrite_path:?} with", self.name);
+            return Ok(None);
+        }
+
+        Ok(Some(seed))
+    }
+
+    /// Copy or move the contents of the target into the rewrite if
+    /// [`Rule::seed_mode`] says so. Returns whether the rewrite was seeded.
+    pub fn seed_rewrite(
+        &self,
+        target_path: &Path,
+        rewrite_path: &Path,
+        fs: &FsDriver,
+    ) -> Result<bool> {
+        let Some(seed) = self.seed_mode(target_path, rewrite_path, fs)? else {
+            return Ok(false);
+        };
+
+        info!(
+            "seeding {} from {} for rule '{}' ({seed})",
+            rewrite_path.display(),
+            target_path.display(),
+            self.name
+        );
+        if let Some(parent) = rewrite_path.parent() {
+            fs.touch_dir(parent)?;
+        }
+        match seed {
+            SeedMode::Copy => fs.copy_recursive(target_path, rewrite_path)?,
+            SeedMode::Move => fs.move_path(target_path, rewrite_path)?,
+        }
+        Ok(true)
+    }
+
+    /// The concrete mode of this rule. `auto` rules are resolved to `file`
+    /// or `directory` by checking the target first, then the rewrite.
+    pub fn resolved_mode(&self, fs: &FsDriver) -> Result<RuleMode> {
+        if self.mode != RuleMode::Auto {
+            return Ok(self.mode);
+        }
+
+        let target_path = fs.fully_expand_path(&self.target)?;
+        let rewrite_path = fs.fully_expand_path(&self.rewrite)?;
+        for path in [&target_path, &rewrite_path] {
+            if path.is_dir() {
+                debug!("{}: detected directory mode from {path:?}", self.name);
+                return Ok(RuleMode::Directory);
+            } else if path.exists() {
+                debug!("{}: detected file mode from {path:?}", self.name);
+                return Ok(RuleMode::File);
+            }
+        }
+
+        Err(eyre!(
+            "{}: cannot detect whether this rule is for a file or a directory, because neither {} nor {} exists! set `mode: file` or `mode: directory` on the rule",
+            self.name,
+            target_path.display(),
+            rewrite_path.display()
+        ))
+    }
+
+    /// The mount flags for this rule's mount options.
+    pub fn mount_flags(&self) -> MsFlags {
+        self.options
+            .iter()
+            .fold(MsFlags::empty(), |flags, option| flags | option.flag())
+    }
+
+    /// Check whether this rule applies to a command, recording each check in
+    /// `reasons`. Returns the rule with its variables expanded if it applies.
+    fn explain(
+        &self,
+        binary: &OsStr,
+        args: &[String],
+        pwd: &Path,
+        fs: &FsDriver,
+        reasons: &mut Vec<String>,
+    ) -> Result<Option<Rule>> {
+        let mut reason = |reason: String| {
+            debug!("{}: {reason}", self.name);
+            reasons.push(reason);
+        };
+
+        if !self.when.is_empty() {
+            if let Some(unmet) = self.when.unmet(&self.name) {
+                reason(format!("env condition not met: {unmet}"));
+                return Ok(None);
+            }
+            reason("env conditions met".into());
+        }
+
+        match self.check_binary(binary, fs)? {
+            BinaryMatch::Any => reason("applies to any binary".into()),
+            BinaryMatch::Only(entry, how) => {
+                reason(format!("binary matches `only` entry {entry:?} by {how}"))
+            }
+            BinaryMatch::Excluded(entry, how) => {
+                reason(format!("binary matches `except` entry {entry:?} by {how}"));
+                return Ok(None);
+            }
+            BinaryMatch::NotListed => {
+                reason(format!("binary {binary:?} doesn't match any `only` entry"));
+                return Ok(None);
+            }
+        }
+
+        if !self.args.is_empty() {
+            if !self.applies_to_args(args)? {
+                reason(format!("args {args:?} don't match {:?}", self.args));
+                return Ok(None);
+            }
+            reason(format!("args match {:?}", self.args));
+        }
+
+        // A variable that isn't set on this machine means the rule isn't
+        // meant for it, so it's skipped rather than failing the whole run.
+        let rule = match self.expand_variables(binary) {
+            Ok(rule) => rule,
+            Err(e) => {
+                warn!("{e}, skipping rule");
+                reason(format!("skipped: {e}"));
+                return Ok(None);
+            }
+        };
+        if let Some(context) = rule.matching_context(&rule.not_context, pwd, fs)? {
+            reason(format!("excluded by `not_context` entry {context:?}"));
+            return Ok(None);
+        }
+        if rule.context.is_empty() {
+            reason("no context, applies everywhere".into());
+        } else if let Some(context) = rule.matching_context(&rule.context, pwd, fs)? {
+            reason(format!("{} is in context {context:?}", pwd.display()));
+        } else {
+            reason(format!("{} is not in any context", pwd.display()));
+            return Ok(None);
+        }
+
+        Ok(Some(rule))
+    }
+
+    /// Expand the rule's variables, and return it only if it applies in the
+    /// current directory.
+    pub fn expand_in_context(&self, binary: &OsStr, fs: &FsDriver) -> Result<Option<Rule>> {
+        let rule = match self.expand_variables(binary) {
+            Ok(rule) => rule,
+            Err(e) => {
+                warn!("{e}, skipping rule");
+                return Ok(None);
+            }
+        };
+
+        if rule.currently_excluded(fs)? {
+            debug!("{}: rule is excluded by not_context!", rule.name);
+            return Ok(None);
+        }
+        if !rule.currently_in_context(fs)? {
+            debug!("{}: rule is NOT in context!", rule.name);
+            return Ok(None);
+        }
+
+        Ok(Some(rule))
+    }
+
     pub fn currently_in_context(&self, fs: &FsDriver) -> Result<bool> {
         if self.context.is_empty() {
             return Ok(true);
         }
 
-        for context in &self.context {
-            debug!("{}: resolving context: {}", self.name, context);
-            let expanded_context = shellexpand::tilde(&context).to_string();
-            let expanded_context = Path::new(&expanded_context).canonicalize()?;
-            let resolved_context = fs.maybe_resolve_symlink(&expanded_context)?;
+        let pwd = std::env::current_dir()?;
+        Ok(self.matching_context(&self.context, &pwd, fs)?.is_some())
+    }
 
-            let pwd = std::env::current_dir()?;
+    pub fn currently_excluded(&self, fs: &FsDriver) -> Result<bool> {
+        let pwd = std::env::current_dir()?;
+        Ok(self
+            .matching_context(&self.not_context, &pwd, fs)?
+            .is_some())
+    }
+
+    /// The first of `contexts` that matches the current directory, if any.
+    pub fn matching_context<'a>(
+        &self,
+        contexts: &'a [String],
+        pwd: &Path,
+        fs: &FsDriver,
+    ) -> Result<Option<&'a str>> {
+        for context in contexts {
+            if self.matches_context(context, pwd, fs)? {
+                return Ok(Some(context));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Test a single context against the current directory. Contexts are
+    /// paths or globs that the current directory must be inside of,
+    /// `git:<glob>` to match the remotes of the current git repository, or
+    /// `marker:<file>` to look for a marker file in the current directory or
+    /// any of its parents.
+    fn matches_context(&self, context: &str, pwd: &Path, fs: &FsDriver) -> Result<bool> {
+        debug!("{}: resolving context: {}", self.name, context);
+
+        if let Some(marker) = context.strip_prefix("marker:") {
+            for dir in pwd.ancestors() {
+                let marker_path = dir.join(marker);
+                if marker_path.exists() {
+                    debug!("{}: found context marker at {marker_path:?}", self.name);
+                    return Ok(true);
+                }
+            }
+            return Ok(false);
+        }
+
+        if let Some(remote) = context.strip_prefix("git:") {
+            let pattern = glob::Pattern::new(remote)?;
+            for remote_url in git_remote_urls(pwd) {
+                debug!("{}: {remote_url} <> {remote}", self.name);
+                if pattern.matches(&remote_url) {
+                    return Ok(true);
+                }
+            }
+            return Ok(false);
+        }
+
+        let expanded_context = shellexpand::tilde(context).to_string();
+        let contexts = if expanded_context.contains(['*', '?', '[']) {
+            glob::glob(&expanded_context)?
+                .filter_map(|entry| match entry {
+                    Ok(path) => Some(path),
+                    Err(e) => {
+                        debug!("{}: skipping unreadable context: {e}", self.name);
+                        None
+                    }
+                })
+                .collect()
+        } else {
+            vec![PathBuf::from(expanded_context)]
+        };
+
+        for context in contexts {
+            let Ok(expanded_context) = context.canonicalize() else {
+                debug!(
+                    "{}: context {context:?} doesn't exist, skipping!",
+                    self.name
+                );
+                continue;
+            };
+            let resolved_context = fs.maybe_resolve_symlink(&expanded_context)?;
 
             debug!(
                 "{}: {} <> {}",
@@ -93,21 +971,112 @@ impl Rule {
     }
 
     pub fn applies_to_binary(&self, program: &OsStr, fs: &FsDriver) -> Result<bool> {
+        Ok(matches!(
+            self.check_binary(program, fs)?,
+            BinaryMatch::Any | BinaryMatch::Only(..)
+        ))
+    }
+
+    /// Check a binary against the rule's `except` and `only` entries.
+    pub fn check_binary(&self, program: &OsStr, fs: &FsDriver) -> Result<BinaryMatch> {
+        for excluded_binary in &self.except {
+            if let Some(how) = self.test_program(program, excluded_binary, fs)? {
+                debug!("{}: binary is excluded by {excluded_binary}!", self.name);
+                return Ok(BinaryMatch::Excluded(excluded_binary.clone(), how));
+            }
+        }
+
         if self.only.is_empty() {
-            return Ok(true);
+            return Ok(BinaryMatch::Any);
         }
 
         for rule_binary in &self.only {
-            if self.test_program(program, &PathBuf::from(rule_binary), fs)? {
+            if let Some(how) = self.test_program(program, rule_binary, fs)? {
                 debug!("{}: rule applies to binary!", self.name);
-                return Ok(true);
+                return Ok(BinaryMatch::Only(rule_binary.clone(), how));
             }
         }
 
-        Ok(false)
+        Ok(BinaryMatch::NotListed)
     }
 
-    fn test_program(&self, program: &OsStr, rule_binary: &Path, fs: &FsDriver) -> Result<bool> {
+    pub fn applies_to_args(&self, args: &[String]) -> Result<bool> {
+        if self.args.is_empty() {
+            return Ok(true);
+        }
+
+        let patterns = self
+            .args
+            .iter()
+            .map(|pattern| glob::Pattern::new(pattern))
+            .collect::<Result<Vec<_>, _>>()?;
+        debug!(
+            "{}: comparing args: args={args:?}, patterns={:?}",
+            self.name, self.args
+        );
+
+        Ok(args_match(&patterns, args))
+    }
+
+    fn test_program(
+        &self,
+        program: &OsStr,
+        rule_binary: &str,
+        fs: &FsDriver,
+    ) -> Result<Option<ProgramMatch>> {
+        if let Some(regex) = rule_binary.strip_prefix("regex:") {
+            let regex = Regex::new(regex)?;
+            let matched =
+                self.test_program_pattern(program, rule_binary, |name| regex.is_match(name));
+            return Ok(matched.then_some(ProgramMatch::Regex));
+        }
+
+        if rule_binary.contains(['*', '?', '[']) {
+            let pattern = glob::Pattern::new(rule_binary)?;
+            let matched =
+                self.test_program_pattern(program, rule_binary, |name| pattern.matches(name));
+            return Ok(matched.then_some(ProgramMatch::Glob));
+        }
+
+        self.test_program_path(program, Path::new(rule_binary), fs)
+    }
+
+    /// Test a program against a glob or regex pattern. The pattern is tested
+    /// against the program as given, its file name, and its path in `$PATH`.
+    fn test_program_pattern<F: Fn(&str) -> bool>(
+        &self,
+        program: &OsStr,
+        rule_binary: &str,
+        matches: F,
+    ) -> bool {
+        let program_path = PathBuf::from(program);
+        let mut candidates = vec![program_path.clone()];
+        if let Some(file_name) = program_path.file_name() {
+            candidates.push(PathBuf::from(file_name));
+        }
+        if let Ok(which_user_program) = which::which(program) {
+            candidates.push(which_user_program);
+        }
+
+        for candidate in candidates {
+            debug!(
+                "{}: comparing binary by pattern: candidate={candidate:?}, rule_binary={rule_binary:?}",
+                self.name
+            );
+            if matches(&candidate.to_string_lossy()) {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    fn test_program_path(
+        &self,
+        program: &OsStr,
+        rule_binary: &Path,
+        fs: &FsDriver,
+    ) -> Result<Option<ProgramMatch>> {
         debug!(
             "{}: testing program: program={program:?}, rule_binary={rule_binary:?}",
             self.name
@@ -117,7 +1086,7 @@ impl Rule {
         if let Some(file_name) = rule_binary.file_name() {
             debug!("{}: comparing file names: program={program:?}, rule binary file_name={file_name:?}", self.name);
             if program == file_name {
-                return Ok(true);
+                return Ok(Some(ProgramMatch::FileName));
             }
         }
 
@@ -125,7 +1094,7 @@ impl Rule {
         if let Some(path) = rule_binary.to_str() {
             debug!("{}: comparing binaries by given paths: program={program:?}, rule_binary={rule_binary:?}", self.name);
             if program == path {
-                return Ok(true);
+                return Ok(Some(ProgramMatch::Path));
             }
         }
 
@@ -134,7 +1103,7 @@ impl Rule {
         if let Ok(expanded_rule_binary) = rule_binary.canonicalize() {
             debug!("{}: comparing binaries by full expansion: expanded_user_program={expanded_user_program:?}, expanded_rule_binary={expanded_rule_binary:?}", self.name);
             if expanded_rule_binary == expanded_user_program {
-                return Ok(true);
+                return Ok(Some(ProgramMatch::CanonicalPath));
             }
 
             // Resolve rule path and program path as symlinks, and compare. ex. /bin/ls == /bin/ls
@@ -142,7 +1111,7 @@ impl Rule {
             let resolved_user_program = fs.maybe_resolve_symlink(&expanded_user_program)?;
             debug!("{}: comparing binaries as resolved symlinks: resolved_user_program={resolved_user_program:?}, resolved_rule_binary={resolved_rule_binary:?}", self.name);
             if resolved_rule_binary == resolved_user_program {
-                return Ok(true);
+                return Ok(Some(ProgramMatch::Symlink));
             }
         } else {
             // If we can't canonicalize the rule binary, try to resolve the
@@ -152,11 +1121,11 @@ impl Rule {
             if let Some(file_name) = resolved_user_program.file_name() {
                 if file_name == rule_binary {
                     debug!("{}: rule binary {rule_binary:?} matches user program file name for {resolved_user_program:?}", self.name);
-                    return Ok(true);
+                    return Ok(Some(ProgramMatch::FileName));
                 }
             } else if rule_binary == resolved_user_program {
                 debug!("{}: rule binary {rule_binary:?} matches user program {resolved_user_program:?}", self.name);
-                return Ok(true);
+                return Ok(Some(ProgramMatch::Path));
             }
         }
 
@@ -167,31 +1136,124 @@ impl Rule {
         if which_rule_binary == which_user_program
             && (which_rule_binary.is_some() || which_user_program.is_some())
         {
-            return Ok(true);
+            return Ok(Some(ProgramMatch::Which));
         }
 
         debug!("{}: rule didn't match anything, does not apply!", self.name);
-        Ok(false)
+        Ok(None)
+    }
+}
+
+/// Match arguments against patterns, where `**` and a trailing `*` match
+/// any number of arguments, and every other pattern matches exactly one.
+fn args_match(patterns: &[glob::Pattern], args: &[String]) -> bool {
+    match patterns.split_first() {
+        None => args.is_empty(),
+        Some((pattern, [])) if pattern.as_str() == "*" => true,
+        Some((pattern, patterns)) if pattern.as_str() == "**" => {
+            (0..=args.len()).any(|skip| args_match(patterns, &args[skip..]))
+        }
+        Some((pattern, patterns)) => match args.split_first() {
+            Some((arg, args)) => pattern.matches(arg) && args_match(patterns, args),
+            None => false,
+        },
+    }
+}
+
+/// The default location of an XDG base directory, for when it isn't set in
+/// the environment.
+fn default_xdg_dir(var: &str) -> Option<PathBuf> {
+    match var {
+        "XDG_CONFIG_HOME" => dirs::config_dir(),
+        "XDG_DATA_HOME" => dirs::data_dir(),
+        "XDG_CACHE_HOME" => dirs::cache_dir(),
+        "XDG_STATE_HOME" => dirs::state_dir(),
+        _ => None,
+    }
+}
+
+/// The remote URLs of the git repository that `dir` is in, if any.
+fn git_remote_urls(dir: &Path) -> Vec<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["config", "--get-regexp", r"^remote\..*\.url$"])
+        .output();
+
+    match output {
+        Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| line.split_once(' '))
+            .map(|(_, url)| url.to_string())
+            .collect(),
+        _ => vec![],
     }
 }
 
 fn default_rule_mode() -> RuleMode {
-    RuleMode::Directory
+    RuleMode::Auto
 }
 
-fn empty_vec<T>() -> Vec<T> {
-    Vec::new()
+/// Either a single string or a list of strings.
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
 }
 
-fn empty_hashmap<K, V>() -> HashMap<K, V> {
-    HashMap::new()
+/// A string, or a number or boolean that's written without quotes.
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged)]
+enum Scalar {
+    String(String),
+    Bool(bool),
+    Integer(i64),
+    Float(f64),
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+/// Deserialise a scalar as a string. Untagged enums buffer their input, so
+/// serde_yaml can't turn unquoted scalars into strings by itself, and TOML
+/// and JSON never do.
+fn scalar_to_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    Ok(match Scalar::deserialize(deserializer)? {
+        Scalar::String(value) => value,
+        Scalar::Bool(value) => value.to_string(),
+        Scalar::Integer(value) => value.to_string(),
+        Scalar::Float(value) => value.to_string(),
+    })
+}
+
+/// Deserialise either a single string or a list of strings.
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, JsonSchema, Serialize, strum::Display,
+)]
 #[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
 pub enum RuleMode {
+    /// Pick `file` or `directory` based on whichever of the target and the
+    /// rewrite already exists.
+    #[default]
+    Auto,
     File,
     Directory,
+    /// Mount an overlayfs over the target directory, with the original
+    /// target as the read-only lower layer and the rewrite as the upper
+    /// layer that receives all writes.
+    Overlay,
+    /// Mount a fresh tmpfs over the target for the lifetime of the
+    /// enclosure. File targets are shadowed by an empty file on a tmpfs.
+    Tmpfs,
+    /// Hide the target from the program entirely. Directories are replaced
+    /// with an empty read-only tmpfs, and files with `/dev/null`.
+    Hide,
 }
 
 impl FromStr for RuleMode {
@@ -199,9 +1261,834 @@ impl FromStr for RuleMode {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
+            "auto" => Ok(RuleMode::Auto),
             "file" => Ok(RuleMode::File),
             "directory" | "dir" => Ok(RuleMode::Directory),
+            "overlay" => Ok(RuleMode::Overlay),
+            "tmpfs" => Ok(RuleMode::Tmpfs),
+            "hide" => Ok(RuleMode::Hide),
             _ => Err(format!("invalid rule mode: {s}")),
         }
     }
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, JsonSchema, Serialize, strum::Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum SeedMode {
+    Copy,
+    Move,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, JsonSchema, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MissingAction {
+    /// Create the missing path.
+    #[default]
+    Create,
+    /// Don't apply the rule.
+    Skip,
+    /// Stop with an error.
+    Error,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, JsonSchema, Serialize)]
+#[serde(from = "OnMissingConfig")]
+pub struct OnMissing {
+    pub target: MissingAction,
+    pub rewrite: MissingAction,
+}
+
+impl OnMissing {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// `on_missing` as written in config files, ie either a single action for
+/// both paths, or separate actions.
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged, deny_unknown_fields)]
+enum OnMissingConfig {
+    Both(MissingAction),
+    Each {
+        #[serde(default)]
+        target: MissingAction,
+        #[serde(default)]
+        rewrite: MissingAction,
+    },
+}
+
+impl From<OnMissingConfig> for OnMissing {
+    fn from(config: OnMissingConfig) -> Self {
+        match config {
+            OnMissingConfig::Both(action) => OnMissing {
+                target: action,
+                rewrite: action,
+            },
+            OnMissingConfig::Each { target, rewrite } => OnMissing { target, rewrite },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, JsonSchema, Serialize, strum::Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum MountOption {
+    Ro,
+    Noexec,
+    Nosuid,
+    Nodev,
+}
+
+impl MountOption {
+    pub fn flag(&self) -> MsFlags {
+        match self {
+            MountOption::Ro => MsFlags::MS_RDONLY,
+            MountOption::Noexec => MsFlags::MS_NOEXEC,
+            MountOption::Nosuid => MsFlags::MS_NOSUID,
+            MountOption::Nodev => MsFlags::MS_NODEV,
+        }
+    }
+}
+
+impl FromStr for MountOption {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ro" => Ok(MountOption::Ro),
+            "noexec" => Ok(MountOption::Noexec),
+            "nosuid" => Ok(MountOption::Nosuid),
+            "nodev" => Ok(MountOption::Nodev),
+            _ => Err(format!("invalid mount option: {s}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use color_eyre::Result;
+
+    fn rule(target: &str, rewrite: &str) -> Rule {
+        Rule {
+            name: "test".into(),
+            target: target.into(),
+            rewrite: rewrite.into(),
+            mode: RuleMode::File,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_plain_targets_are_not_expanded() -> Result<()> {
+        let rule = rule("~/.tmux.conf", "~/.config/tmux/tmux.conf");
+        assert!(!rule.has_glob_target());
+
+        let expanded = rule.expand_target(&FsDriver::new())?;
+        assert_eq!(expanded.len(), 1);
+        assert_eq!(expanded[0].target, "~/.tmux.conf");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_glob_targets_expand_to_each_match() -> Result<()> {
+        let driver = FsDriver::new();
+        let name = "test-glob-targets";
+        let root = driver.container_root(name);
+        driver.setup_root(name)?;
+        for file in ["debug1", "debug2", "debug9"] {
+            driver.touch(&append_all(&root, vec![file]))?;
+        }
+
+        let rule = rule(
+            &format!("{}/debug[1-4]", root.display()),
+            &format!("{}/rewrite", root.display()),
+        );
+        assert!(rule.has_glob_target());
+
+        let mut expanded = rule.expand_target(&driver)?;
+        expanded.sort_by(|a, b| a.target.cmp(&b.target));
+        let pairs: Vec<_> = expanded
+            .iter()
+            .map(|rule| (rule.target.clone(), rule.rewrite.clone()))
+            .collect();
+        driver.cleanup_root(name)?;
+
+        assert_eq!(
+            pairs,
+            vec![
+                (
+                    format!("{}/debug1", root.display()),
+                    format!("{}/rewrite/debug1", root.display())
+                ),
+                (
+                    format!("{}/debug2", root.display()),
+                    format!("{}/rewrite/debug2", root.display())
+                ),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_only_matches_globs_and_regexes() -> Result<()> {
+        let fs = FsDriver::new();
+        let mut rule = rule("~/.kube", "~/.config/kube");
+
+        rule.only = vec!["kubectl-*".into()];
+        assert!(rule.applies_to_binary(OsStr::new("kubectl-ctx"), &fs)?);
+        assert!(rule.applies_to_binary(OsStr::new("/opt/bin/kubectl-ns"), &fs)?);
+        assert!(!rule.applies_to_binary(OsStr::new("boxxy-kubectl-ctx"), &fs)?);
+
+        rule.only = vec![r"regex:^python3\.\d+$".into()];
+        assert!(rule.applies_to_binary(OsStr::new("python3.12"), &fs)?);
+        assert!(!rule.applies_to_binary(OsStr::new("python3-config"), &fs)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_except_excludes_binaries() -> Result<()> {
+        let fs = FsDriver::new();
+        let mut rule = rule("~/.kube", "~/.config/kube");
+
+        rule.except = vec!["kubectl-secret*".into()];
+        assert!(rule.applies_to_binary(OsStr::new("kubectl-ctx"), &fs)?);
+        assert!(!rule.applies_to_binary(OsStr::new("kubectl-secrets"), &fs)?);
+
+        rule.only = vec!["kubectl-*".into()];
+        assert!(rule.applies_to_binary(OsStr::new("kubectl-ctx"), &fs)?);
+        assert!(!rule.applies_to_binary(OsStr::new("kubectl-secrets"), &fs)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tmpfs_rules_need_no_rewrite() -> Result<()> {
+        let rules: BoxxyRules = serde_yaml::from_str(
+            r#"
+            rules:
+            - name: "throwaway python history"
+              target: "~/.python_history"
+              mode: "tmpfs"
+              size: "1m"
+            "#,
+        )?;
+
+        let rule = &rules.rules[0];
+        assert_eq!(rule.mode, RuleMode::Tmpfs);
+        assert_eq!(rule.rewrite, "");
+        assert_eq!(rule.size.as_deref(), Some("1m"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tmpfs_file_targets_are_resolved() -> Result<()> {
+        let fs = FsDriver::new();
+        let name = "test-tmpfs-file-targets";
+        let root = fs.container_root(name);
+        fs.setup_root(name)?;
+        std::fs::create_dir_all(root.join("dir"))?;
+        std::fs::write(root.join("history"), "")?;
+
+        let tmpfs_rule = |target: &str, file: bool| Rule {
+            name: target.into(),
+            target: root.join(target).to_string_lossy().to_string(),
+            mode: RuleMode::Tmpfs,
+            file,
+            ..Default::default()
+        };
+        let resolved = |rule: Rule| -> Result<bool> { Ok(rule.resolve(&fs)?[0].file) };
+
+        let existing_file = resolved(tmpfs_rule("history", false));
+        let existing_dir = resolved(tmpfs_rule("dir", false));
+        let missing_file = resolved(tmpfs_rule("missing_history", true));
+        let missing_dir = resolved(tmpfs_rule("missing_dir", false));
+        fs.cleanup_root(name)?;
+
+        assert!(existing_file?);
+        assert!(!existing_dir?);
+        assert!(missing_file?);
+        assert!(!missing_dir?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_seed_only_fills_empty_rewrites() -> Result<()> {
+        let fs = FsDriver::new();
+        let name = "test-seed-rewrites";
+        let root = fs.container_root(name);
+        fs.setup_root(name)?;
+        let full = root.join("full");
+        let empty = root.join("empty");
+        let taken = root.join("taken");
+        std::fs::write(&full, "config")?;
+        std::fs::write(&empty, "")?;
+        std::fs::write(&taken, "other config")?;
+        let seed_rule = |seed: SeedMode, mode: RuleMode| Rule {
+            name: "seed".into(),
+            mode,
+            seed: Some(seed),
+            ..Default::default()
+        };
+        let copy = seed_rule(SeedMode::Copy, RuleMode::File);
+        let seed_mode = |rule: &Rule, target: &Path, rewrite: &Path| -> Result<Option<SeedMode>> {
+            rule.seed_mode(target, rewrite, &fs)
+        };
+
+        let from_empty = seed_mode(&copy, &empty, &root.join("a"));
+        let into_taken = seed_mode(&copy, &full, &taken);
+        let overlay = seed_mode(
+            &seed_rule(SeedMode::Copy, RuleMode::Overlay),
+            &full,
+            &root.join("a"),
+        );
+        let copied = copy.seed_rewrite(&full, &root.join("copied"), &fs);
+        let moved = seed_rule(SeedMode::Move, RuleMode::File).seed_rewrite(
+            &full,
+            &root.join("nested").join("moved"),
+            &fs,
+        );
+        let copied_contents = std::fs::read_to_string(root.join("copied")).ok();
+        let moved_contents = std::fs::read_to_string(root.join("nested").join("moved")).ok();
+        let full_exists = full.exists();
+        fs.cleanup_root(name)?;
+
+        assert_eq!(from_empty?, None);
+        assert_eq!(into_taken?, None);
+        assert_eq!(overlay?, None);
+        assert!(copied?);
+        assert!(moved?);
+        assert_eq!(copied_contents.as_deref(), Some("config"));
+        assert_eq!(moved_contents.as_deref(), Some("config"));
+        assert!(!full_exists);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_seeded_rewrites_are_not_missing() -> Result<()> {
+        let fs = FsDriver::new();
+        let name = "test-seed-missing-rewrite";
+        let root = fs.container_root(name);
+        fs.setup_root(name)?;
+        let target = root.join("target");
+        std::fs::write(&target, "config")?;
+        let mut rule = Rule {
+            name: "seed".into(),
+            target: target.to_string_lossy().to_string(),
+            rewrite: root.join("rewrite").to_string_lossy().to_string(),
+            seed: Some(SeedMode::Copy),
+            on_missing: OnMissing {
+                target: MissingAction::Create,
+                rewrite: MissingAction::Error,
+            },
+            ..Default::default()
+        };
+
+        let seeded = rule.check_missing_paths(&fs);
+        std::fs::write(&target, "")?;
+        let empty_target = rule.check_missing_paths(&fs);
+        rule.seed = None;
+        let unseeded = rule.check_missing_paths(&fs);
+        fs.cleanup_root(name)?;
+
+        assert!(seeded?);
+        assert!(empty_target.is_err());
+        assert!(unseeded.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mount_options_combine_into_flags() -> Result<()> {
+        let mut rule = rule("~/.aws", "~/.config/aws");
+        assert_eq!(rule.mount_flags(), MsFlags::empty());
+
+        rule.options = vec!["ro".parse().unwrap(), MountOption::Noexec];
+        assert_eq!(rule.mount_flags(), MsFlags::MS_RDONLY | MsFlags::MS_NOEXEC);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_auto_mode_detects_files_and_directories() -> Result<()> {
+        let driver = FsDriver::new();
+        let name = "test-auto-mode";
+        let root = driver.container_root(name);
+        driver.setup_root(name)?;
+        driver.touch(&append_all(&root, vec!["file"]))?;
+        driver.touch_dir(&append_all(&root, vec!["dir"]))?;
+
+        let path = |part: &str| format!("{}/{part}", root.display());
+        let mut auto_rule = rule(&path("file"), &path("missing"));
+        auto_rule.mode = RuleMode::Auto;
+        let file_mode = auto_rule.resolved_mode(&driver);
+
+        auto_rule.target = path("missing");
+        auto_rule.rewrite = path("dir");
+        let directory_mode = auto_rule.resolved_mode(&driver);
+
+        auto_rule.rewrite = path("also-missing");
+        let missing_mode = auto_rule.resolved_mode(&driver);
+        driver.cleanup_root(name)?;
+
+        assert_eq!(file_mode?, RuleMode::File);
+        assert_eq!(directory_mode?, RuleMode::Directory);
+        assert!(missing_mode.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_later_rules_shadow_earlier_targets() -> Result<()> {
+        let rules: BoxxyRules = serde_yaml::from_str(
+            r#"
+            rules:
+            - name: "global aws"
+              target: "~/.aws"
+              rewrite: "~/.config/aws"
+            - name: "kube"
+              target: "~/.kube"
+              rewrite: "~/.config/kube"
+            - name: "project aws"
+              target: "~/.aws"
+              rewrite: "./.aws"
+            "#,
+        )?;
+
+        let applicable = rules.get_all_applicable_rules(&Command::new("aws"), &FsDriver::new())?;
+        let names: Vec<_> = applicable.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, vec!["kube", "project aws"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_profiles_layer_rules_on_top() -> Result<()> {
+        let mut rules: BoxxyRules = serde_yaml::from_str(
+            r#"
+            rules:
+            - name: "aws"
+              target: "~/.aws"
+              rewrite: "~/.config/aws"
+            profiles:
+              work:
+                rules:
+                - name: "kube"
+                  target: "~/.kube"
+                  rewrite: "~/.config/kube/work"
+                env:
+                  AWS_PROFILE: "work"
+            "#,
+        )?;
+
+        assert!(rules.activate_profile("personal").is_none());
+        let profile = rules.activate_profile("work").unwrap();
+        assert_eq!(profile.env["AWS_PROFILE"], "work");
+        let names: Vec<_> = rules.rules.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, vec!["aws", "kube"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_when_conditions_gate_on_env_vars() -> Result<()> {
+        std::env::set_var("BOXXY_TEST_WHEN_CI", "true");
+        std::env::remove_var("BOXXY_TEST_WHEN_MISSING");
+
+        let mut condition = RuleCondition::default();
+        assert!(condition.is_empty());
+        assert!(condition.is_met("test"));
+
+        condition.env = HashMap::from([("BOXXY_TEST_WHEN_CI".into(), "true".into())]);
+        condition.set = vec!["BOXXY_TEST_WHEN_CI".into()];
+        condition.unset = vec!["BOXXY_TEST_WHEN_MISSING".into()];
+        assert!(condition.is_met("test"));
+
+        condition.env = HashMap::from([("BOXXY_TEST_WHEN_CI".into(), "false".into())]);
+        assert!(!condition.is_met("test"));
+
+        condition.env.clear();
+        condition.set = vec!["BOXXY_TEST_WHEN_MISSING".into()];
+        assert!(!condition.is_met("test"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_context_globs_markers_and_missing_paths() -> Result<()> {
+        let driver = FsDriver::new();
+        let name = "test-contexts";
+        let root = driver.container_root(name);
+        driver.setup_root(name)?;
+        let project = append_all(&root, vec!["Projects", "acme", "client-a", "src"]);
+        driver.touch_dir(&project)?;
+        driver.touch(&append_all(
+            &root,
+            vec!["Projects", "acme", ".boxxy-context"],
+        ))?;
+        let project = project.canonicalize()?;
+
+        let rule = rule("~/.npmrc", "~/.config/npm/npmrc");
+        let glob_context = format!("{}/Projects/*/client-*", root.display());
+        let matches_glob = rule.matches_context(&glob_context, &project, &driver);
+        let matches_marker = rule.matches_context("marker:.boxxy-context", &project, &driver);
+        let matches_other_marker = rule.matches_context("marker:.not-here", &project, &driver);
+        let missing_context = format!("{}/does-not-exist", root.display());
+        let matches_missing = rule.matches_context(&missing_context, &project, &driver);
+        driver.cleanup_root(name)?;
+
+        assert!(matches_glob?);
+        assert!(matches_marker?);
+        assert!(!matches_other_marker?);
+        assert!(!matches_missing?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_not_context_excludes_rules() -> Result<()> {
+        let rules: BoxxyRules = serde_yaml::from_str(
+            r#"
+            rules:
+            - name: "npmrc"
+              target: "~/.npmrc"
+              rewrite: "~/.config/npm/npmrc"
+            - name: "excluded npmrc"
+              target: "~/.yarnrc"
+              rewrite: "~/.config/yarn/yarnrc"
+              not_context:
+              - "/"
+            "#,
+        )?;
+
+        let applicable = rules.get_all_applicable_rules(&Command::new("npm"), &FsDriver::new())?;
+        let names: Vec<_> = applicable.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, vec!["npmrc"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_variables_are_expanded() -> Result<()> {
+        std::env::set_var("BOXXY_TEST_EXPAND_TOOL", "tool");
+        std::env::remove_var("BOXXY_TEST_EXPAND_MISSING");
+
+        let mut rule = rule(
+            "~/.${BOXXY_TEST_EXPAND_TOOL}",
+            "${BOXXY_TEST_EXPAND_MISSING:-/fallback}/$COMMAND",
+        );
+        rule.context = vec!["$PROJECT_ROOT/src".into()];
+        rule.env = HashMap::from([(
+            "TOOL_HOME".into(),
+            EnvValue::Set("$PROJECT_ROOT/.tool".into()),
+        )]);
+        rule.source = Some(PathBuf::from("/projects/acme/boxxy.yaml"));
+
+        let expanded = rule.expand_variables(OsStr::new("/usr/bin/tool"))?;
+        let home = dirs::home_dir().unwrap();
+        assert_eq!(expanded.target, format!("{}/.tool", home.display()));
+        assert_eq!(expanded.rewrite, "/fallback/tool");
+        assert_eq!(expanded.context, vec!["/projects/acme/src"]);
+        assert_eq!(
+            expanded.env["TOOL_HOME"],
+            EnvValue::Set("$PROJECT_ROOT/.tool".into())
+        );
+        assert_eq!(
+            expanded.expand_env(OsStr::new("/usr/bin/tool"), &|_| None)?["TOOL_HOME"],
+            EnvValue::Set("/projects/acme/.tool".into())
+        );
+
+        rule.target = "$BOXXY_TEST_EXPAND_MISSING".into();
+        assert!(rule.expand_variables(OsStr::new("tool")).is_err());
+
+        // Rules that can't be expanded on this machine are skipped.
+        rule.context = vec![];
+        let rules = BoxxyRules {
+            rules: vec![rule],
+            ..Default::default()
+        };
+        let explanations = rules.explain(&Command::new("tool"), &FsDriver::new())?;
+        assert!(explanations[0].applied.is_none());
+        assert!(explanations[0]
+            .reasons
+            .last()
+            .unwrap()
+            .starts_with("skipped: "));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_env_values_modify_current_values() -> Result<()> {
+        let rules: BoxxyRules = serde_yaml::from_str(
+            r#"
+            rules:
+            - name: "tool"
+              target: "~/.tool"
+              rewrite: "~/.config/tool"
+              env:
+                TOOL_MODE: "boxxed"
+                PATH:
+                  prepend: "/opt/tool/bin"
+                LD_LIBRARY_PATH:
+                  append: "/opt/tool/lib"
+                LD_PRELOAD:
+                  unset: true
+            "#,
+        )?;
+        let env = &rules.rules[0].env;
+
+        assert_eq!(
+            env["TOOL_MODE"].apply(Some("old")).as_deref(),
+            Some("boxxed")
+        );
+        assert_eq!(
+            env["PATH"].apply(Some("/usr/bin")).as_deref(),
+            Some("/opt/tool/bin:/usr/bin")
+        );
+        assert_eq!(
+            env["LD_LIBRARY_PATH"].apply(None).as_deref(),
+            Some("/opt/tool/lib")
+        );
+        assert_eq!(env["LD_PRELOAD"].apply(Some("libfoo.so")), None);
+
+        let mut rule = rules.rules[0].clone();
+        rule.env = HashMap::from([("PATH".into(), EnvValue::Set("/opt/tool/bin:$PATH".into()))]);
+        let current_env = |var: &str| (var == "PATH").then(|| "/profile/bin:/usr/bin".to_string());
+        assert_eq!(
+            rule.expand_env(OsStr::new("tool"), &current_env)?["PATH"],
+            EnvValue::Set("/opt/tool/bin:/profile/bin:/usr/bin".into())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_env_values_can_be_numbers_and_booleans() -> Result<()> {
+        let yaml: BoxxyRules = serde_yaml::from_str(
+            r#"
+            rules:
+            - name: "debug"
+              target: "~/.tool"
+              rewrite: "~/.config/tool"
+              env:
+                DEBUG: 1
+                VERBOSE: true
+                RATIO: 0.5
+            "#,
+        )?;
+        let json: BoxxyRules = serde_json::from_str(
+            r#"{"rules": [{"name": "debug", "target": "~/.tool", "env": {"DEBUG": 1, "VERBOSE": true}}]}"#,
+        )?;
+
+        for env in [&yaml.rules[0].env, &json.rules[0].env] {
+            assert_eq!(env["DEBUG"], EnvValue::Set("1".into()));
+            assert_eq!(env["VERBOSE"], EnvValue::Set("true".into()));
+        }
+        assert_eq!(yaml.rules[0].env["RATIO"], EnvValue::Set("0.5".into()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_env_overrides_env_files() -> Result<()> {
+        let fs = FsDriver::new();
+        let name = "test-env-files";
+        let root = fs.container_root(name);
+        fs.setup_root(name)?;
+        std::fs::write(
+            root.join("prod.env"),
+            "AWS_PROFILE=prod\nAWS_REGION=us-east-1\n",
+        )?;
+        std::fs::write(root.join("broken.env"), "this is not dotenv\n")?;
+        let mut rule = rule("~/.aws", "~/.config/aws");
+        rule.name = "aws".into();
+        rule.env_file = vec![root.join("prod.env").to_string_lossy().to_string()];
+        rule.env = HashMap::from([
+            ("AWS_PROFILE".into(), EnvValue::Set("override".into())),
+            ("TOOL".into(), EnvValue::Set("$AWS_REGION".into())),
+        ]);
+
+        let changes = rule.env_changes(OsStr::new("aws"), &fs, &|_| None);
+        rule.env_file = vec![root.join("missing.env").to_string_lossy().to_string()];
+        let missing = rule.load_env_files(&fs);
+        rule.env_file = vec![root.join("broken.env").to_string_lossy().to_string()];
+        let broken = rule.load_env_files(&fs);
+        fs.cleanup_root(name)?;
+
+        let changes = changes?;
+        assert_eq!(changes["AWS_PROFILE"].as_deref(), Some("override"));
+        assert_eq!(changes["AWS_REGION"].as_deref(), Some("us-east-1"));
+        assert_eq!(changes["TOOL"].as_deref(), Some("us-east-1"));
+        let missing = missing.unwrap_err().to_string();
+        assert!(missing.starts_with("aws: could not read env file"));
+        let broken = broken.unwrap_err().to_string();
+        assert!(broken.starts_with("aws: could not parse env file"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_env_file_takes_one_or_many_paths() -> Result<()> {
+        let rules: BoxxyRules = serde_yaml::from_str(
+            r#"
+            rules:
+            - name: "aws prod"
+              target: "~/.aws"
+              rewrite: "~/.config/aws"
+              env_file: "~/.config/aws/prod.env"
+            - name: "aws staging"
+              target: "~/.aws"
+              rewrite: "~/.config/aws"
+              env_file:
+              - "~/.config/aws/common.env"
+              - "~/.config/aws/staging.env"
+            "#,
+        )?;
+
+        assert_eq!(rules.rules[0].env_file, vec!["~/.config/aws/prod.env"]);
+        assert_eq!(rules.rules[1].env_file.len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_args_match_patterns() -> Result<()> {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        let mut rule = rule("~/.gitconfig", "~/.config/git/commit-config");
+        assert!(rule.applies_to_args(&args(&["status"]))?);
+
+        rule.args = vec!["commit".into(), "**".into()];
+        assert!(rule.applies_to_args(&args(&["commit"]))?);
+        assert!(rule.applies_to_args(&args(&["commit", "-m", "hi"]))?);
+        assert!(!rule.applies_to_args(&args(&["status"]))?);
+
+        rule.args = vec!["commit".into(), "*".into()];
+        assert!(rule.applies_to_args(&args(&["commit"]))?);
+        assert!(rule.applies_to_args(&args(&["commit", "-m", "msg"]))?);
+        assert!(!rule.applies_to_args(&args(&["status"]))?);
+
+        rule.args = vec!["*".into(), "-a".into()];
+        assert!(rule.applies_to_args(&args(&["commit", "-a"]))?);
+        assert!(!rule.applies_to_args(&args(&["-a"]))?);
+
+        rule.args = vec!["**".into(), "--profile=prod".into(), "**".into()];
+        assert!(rule.applies_to_args(&args(&["s3", "ls", "--profile=prod"]))?);
+        assert!(!rule.applies_to_args(&args(&["s3", "ls", "--profile=dev"]))?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_on_missing_skips_and_errors() -> Result<()> {
+        let rules: BoxxyRules = serde_yaml::from_str(
+            r#"
+            rules:
+            - name: "skip"
+              target: "/tmp/boxxy-test-on-missing/target"
+              rewrite: "/tmp/boxxy-test-on-missing/rewrite"
+              on_missing: "skip"
+            - name: "error"
+              target: "/tmp/boxxy-test-on-missing/target"
+              rewrite: "/tmp/boxxy-test-on-missing/rewrite"
+              on_missing:
+                rewrite: "error"
+            - name: "create"
+              target: "/tmp/boxxy-test-on-missing/target"
+              rewrite: "/tmp/boxxy-test-on-missing/rewrite"
+            "#,
+        )?;
+        let fs = FsDriver::new();
+
+        assert_eq!(
+            rules.rules[1].on_missing,
+            OnMissing {
+                target: MissingAction::Create,
+                rewrite: MissingAction::Error,
+            }
+        );
+        assert!(serde_yaml::from_str::<OnMissing>("targt: skip").is_err());
+        assert!(!rules.rules[0].check_missing_paths(&fs)?);
+        let err = rules.rules[1].check_missing_paths(&fs).unwrap_err();
+        assert!(err.to_string().contains("rule 'error'"));
+        assert!(rules.rules[2].check_missing_paths(&fs)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_explain_records_errors_per_rule() -> Result<()> {
+        let mut broken = rule("~/.broken", "~/.config/broken");
+        broken.only = vec!["regex:(".into()];
+        let rules = BoxxyRules {
+            rules: vec![broken, rule("~/.fine", "~/.config/fine")],
+            ..Default::default()
+        };
+        let command = Command::new("tool");
+
+        let explanations = rules.explain(&command, &FsDriver::new())?;
+
+        assert!(explanations[0].applied.is_none());
+        assert!(explanations[0].reasons[0].starts_with("error: "));
+        assert!(explanations[1].applied.is_some());
+        assert!(rules
+            .get_all_applicable_rules(&command, &FsDriver::new())
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_explain_gives_reasons() -> Result<()> {
+        let rules: BoxxyRules = serde_yaml::from_str(
+            r#"
+            rules:
+            - name: "kube"
+              target: "~/.kube"
+              rewrite: "~/.config/kube"
+              only: ["kubectl"]
+            - name: "aws"
+              target: "~/.aws"
+              rewrite: "~/.config/aws"
+              only: ["aws"]
+            - name: "ci"
+              target: "~/.ci"
+              mode: "tmpfs"
+              when:
+                env:
+                  BOXXY_TEST_EXPLAIN_UNSET: "1"
+            "#,
+        )?;
+        let mut command = Command::new("kubectl");
+        command.args(["get", "pods"]);
+
+        let explanations = rules.explain(&command, &FsDriver::new())?;
+
+        assert!(explanations[0].applied.is_some());
+        assert!(explanations[0]
+            .reasons
+            .iter()
+            .any(|reason| reason.contains("\"kubectl\" by file name")));
+        assert!(explanations[1].applied.is_none());
+        assert!(explanations[1]
+            .reasons
+            .iter()
+            .any(|reason| reason.contains("doesn't match any `only` entry")));
+        assert!(explanations[2].applied.is_none());
+        assert!(explanations[2]
+            .reasons
+            .iter()
+            .any(|reason| reason.contains("BOXXY_TEST_EXPLAIN_UNSET=\"1\", but it's unset")));
+
+        Ok(())
+    }
+}
@@ -1,18 +1,25 @@
 use std::collections::HashMap;
 use std::io::IsTerminal;
 use std::path::PathBuf;
+use std::process::Command;
 
 use clap::{ArgAction, Parser, Subcommand};
 use color_eyre::Result;
+use eyre::eyre;
 use log::*;
+use owo_colors::OwoColorize;
 use scanner::App;
 
+use crate::config::validate::Severity;
 use crate::config::BoxxyConfig;
-use crate::enclosure::rule::{BoxxyRules, Rule, RuleMode};
+use crate::enclosure::fs::FsDriver;
+use crate::enclosure::rule::{BoxxyRules, EnvValue, Rule, RuleMode};
+use crate::migrate::Migrator;
 use crate::scanner::Scanner;
 
 pub mod config;
 pub mod enclosure;
+pub mod migrate;
 pub mod scanner;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -84,6 +91,14 @@ pub struct Args {
     )]
     pub no_config: bool,
 
+    #[arg(
+        short = 'p',
+        long = "profile",
+        env = "BOXXY_PROFILE",
+        help = "Activate the named profile from the `profiles:` section of the config files."
+    )]
+    pub profile: Option<String>,
+
     #[arg(
         short = 'r',
         long = "rule",
@@ -104,7 +119,10 @@ pub enum BoxxySubcommand {
         subcommand_negates_reqs = true,
         aliases = &["cfg", "conf", "c"]
     )]
-    Config,
+    Config {
+        #[command(subcommand)]
+        action: Option<ConfigSubcommand>,
+    },
     #[command(
         name = "scan",
         about = "Scan your homedir for applications that may benefit from boxxy.",
@@ -112,26 +130,201 @@ pub enum BoxxySubcommand {
         aliases = &["s"]
     )]
     Scan,
+    #[command(
+        name = "migrate",
+        about = "Move existing files from rule targets into their rewrites.",
+        subcommand_negates_reqs = true,
+        aliases = &["m"]
+    )]
+    Migrate {
+        #[arg(
+            long = "dry-run",
+            help = "Only report what would be moved, without touching anything."
+        )]
+        dry_run: bool,
+        #[arg(long = "undo", help = "Move the files from the last migration back.")]
+        undo: bool,
+        #[arg(
+            long = "scan",
+            help = "Migrate using the rules `boxxy scan` would suggest, instead of the config files."
+        )]
+        scan: bool,
+    },
+    #[command(
+        name = "explain",
+        about = "Show which rules would apply to a command and why, without running it.",
+        subcommand_negates_reqs = true,
+        aliases = &["e"]
+    )]
+    Explain {
+        #[arg(
+            short = 'C',
+            long = "cwd",
+            help = "Explain the command as if it was run in this directory, instead of the current one."
+        )]
+        cwd: Option<PathBuf>,
+        #[arg(
+            trailing_var_arg = true,
+            name = "COMMAND TO EXPLAIN",
+            required = true,
+            help = "The command to explain, ex. `aws configure`."
+        )]
+        command_with_args: Vec<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ConfigSubcommand {
+    #[command(
+        name = "validate",
+        about = "Check the config files for mistakes, exiting nonzero if any are broken."
+    )]
+    Validate,
+    #[command(
+        name = "schema",
+        about = "Print the JSON Schema for config files, ex. for yaml-language-server."
+    )]
+    Schema,
+    #[command(
+        name = "add",
+        about = "Add a rule to the global config, or to the nearest project config with --project."
+    )]
+    Add(AddRuleArgs),
+}
+
+#[derive(clap::Args)]
+pub struct AddRuleArgs {
+    #[arg(long = "name", help = "The name of the rule.")]
+    pub name: String,
+
+    #[arg(long = "target", help = "The path to shadow, ex. `~/.aws`.")]
+    pub target: String,
+
+    #[arg(
+        long = "rewrite",
+        default_value = "",
+        hide_default_value = true,
+        help = "The path to shadow the target with, ex. `~/.config/aws`."
+    )]
+    pub rewrite: String,
+
+    #[arg(long = "mode", default_value = "auto", help = "The mode of the rule.")]
+    pub mode: RuleMode,
+
+    #[arg(
+        long = "context",
+        help = "A context where the rule applies. Can be repeated.",
+        action = ArgAction::Append
+    )]
+    pub context: Vec<String>,
+
+    #[arg(
+        long = "only",
+        help = "A binary the rule applies to. Can be repeated.",
+        action = ArgAction::Append
+    )]
+    pub only: Vec<String>,
+
+    #[arg(
+        long = "env",
+        help = "An env var to set when the rule applies, as `KEY=value`. Can be repeated.",
+        action = ArgAction::Append
+    )]
+    pub env: Vec<String>,
+
+    #[arg(
+        long = "project",
+        default_value = "false",
+        help = "Add the rule to the nearest project config instead of the global config."
+    )]
+    pub project: bool,
+}
+
+impl AddRuleArgs {
+    fn to_rule(&self) -> Result<Rule> {
+        let mut env = HashMap::new();
+        for var in &self.env {
+            let Some((key, value)) = var.split_once('=') else {
+                return Err(eyre!("invalid env var {var:?}, expected `KEY=value`"));
+            };
+            env.insert(key.to_string(), EnvValue::Set(value.to_string()));
+        }
+
+        Ok(Rule {
+            name: self.name.clone(),
+            target: self.target.clone(),
+            rewrite: self.rewrite.clone(),
+            mode: self.mode,
+            context: self.context.clone(),
+            only: self.only.clone(),
+            env,
+            ..Default::default()
+        })
+    }
 }
 
 fn main() -> Result<()> {
     // Fetch command to run
-    let cfg = Args::parse();
+    let mut cfg = Args::parse();
     setup_logging(&cfg)?;
 
-    if let Some(cmd) = cfg.command {
+    if let Some(cmd) = cfg.command.take() {
         match cmd {
-            BoxxySubcommand::Config => {
+            BoxxySubcommand::Config { action: None } => {
                 for config_path in BoxxyConfig::rule_paths()? {
                     let mut printer = bat::PrettyPrinter::new();
                     printer.input_file(config_path).print()?;
                 }
                 return Ok(());
             }
+            BoxxySubcommand::Config {
+                action: Some(ConfigSubcommand::Validate),
+            } => {
+                return validate_config(&cfg);
+            }
+            BoxxySubcommand::Config {
+                action: Some(ConfigSubcommand::Schema),
+            } => {
+                println!("{}", BoxxyConfig::json_schema()?);
+                return Ok(());
+            }
+            BoxxySubcommand::Config {
+                action: Some(ConfigSubcommand::Add(add)),
+            } => {
+                let path = BoxxyConfig::add_rule(&add.to_rule()?, add.project)?;
+                info!("added rule '{}' to {}", add.name, path.display());
+                return Ok(());
+            }
             BoxxySubcommand::Scan => {
                 let apps = Scanner::new().scan()?;
                 return scan_homedir(apps);
             }
+            BoxxySubcommand::Migrate {
+                dry_run,
+                undo,
+                scan,
+            } => {
+                let migrator = Migrator::new(dry_run)?;
+                if undo {
+                    return migrator.undo();
+                }
+                let rules = if scan {
+                    let apps = Scanner::new().scan()?;
+                    BoxxyRules {
+                        rules: apps.iter().flat_map(App::rules).collect(),
+                        ..Default::default()
+                    }
+                } else {
+                    BoxxyConfig::load_rules(&cfg)?.0
+                };
+                return migrator.migrate(&rules.get_rules_in_context(&FsDriver::new())?);
+            }
+            BoxxySubcommand::Explain {
+                cwd,
+                command_with_args,
+            } => {
+                return explain_command(&cfg, cwd, &command_with_args);
+            }
         }
     }
 
@@ -172,36 +365,155 @@ fn setup_logging(cfg: &Args) -> Result<()> {
     Ok(())
 }
 
+fn validate_config(cfg: &Args) -> Result<()> {
+    let mut errors = 0;
+    let mut warnings = 0;
+
+    let mut diagnostics = vec![];
+    for config_path in BoxxyConfig::rule_paths()? {
+        info!("checking {}", config_path.display());
+        match BoxxyConfig::validate_path(&config_path) {
+            Ok(config_diagnostics) => diagnostics.extend(config_diagnostics),
+            Err(e) => {
+                errors += 1;
+                error!("{e}");
+            }
+        }
+    }
+    if let Err(e) = BoxxyConfig::load_rules_from_cli_flag(&cfg.arg_rules) {
+        errors += 1;
+        error!("{e}");
+    }
+
+    for diagnostic in diagnostics {
+        match diagnostic.severity {
+            Severity::Warning => {
+                warnings += 1;
+                warn!("{diagnostic}");
+            }
+            Severity::Error => {
+                errors += 1;
+                error!("{diagnostic}");
+            }
+        }
+    }
+
+    info!("found {errors} error(s) and {warnings} warning(s)");
+    if errors > 0 {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+fn explain_command(cfg: &Args, cwd: Option<PathBuf>, command_with_args: &[String]) -> Result<()> {
+    // Project configs, contexts and `$PROJECT_ROOT` all go by the current
+    // directory, so moving there explains everything the way a real run
+    // would see it.
+    if let Some(cwd) = cwd {
+        std::env::set_current_dir(&cwd)
+            .map_err(|e| eyre!("can't explain in {}: {e}", cwd.display()))?;
+    }
+    let fs = FsDriver::new();
+    let (rules, profile) = BoxxyConfig::load_rules(cfg)?;
+    let mut command = Command::new(&command_with_args[0]);
+    command.args(&command_with_args[1..]);
+
+    let explanations = rules.explain(&command, &fs)?;
+    let applied: Vec<Rule> = explanations
+        .iter()
+        .filter_map(|explanation| explanation.applied.clone())
+        .collect();
+    let final_rules = BoxxyRules::drop_shadowed_targets(applied, &fs)?;
+
+    let stdout = owo_colors::Stream::Stdout;
+    println!(
+        "rules for `{}` in {}:",
+        command_with_args.join(" "),
+        std::env::current_dir()?.display()
+    );
+    for explanation in &explanations {
+        let rule = &explanation.rule;
+        let source = match &rule.source {
+            Some(source) => source.display().to_string(),
+            None => "--rule".into(),
+        };
+        let shadowed =
+            explanation.applied.is_some() && !final_rules.iter().any(|r| r.name == rule.name);
+        let status = if shadowed {
+            "~".if_supports_color(stdout, |text| text.yellow())
+                .to_string()
+        } else if explanation.applied.is_some() {
+            "✓"
+                .if_supports_color(stdout, |text| text.green())
+                .to_string()
+        } else {
+            "✗".if_supports_color(stdout, |text| text.red()).to_string()
+        };
+
+        println!();
+        println!(
+            "{status} {} ({source})",
+            rule.name.if_supports_color(stdout, |text| text.bold())
+        );
+        for reason in &explanation.reasons {
+            println!("    {reason}");
+        }
+        if shadowed {
+            println!("    shadowed by a later rule with the same target");
+        }
+    }
+
+    println!();
+    println!("mount plan:");
+    if final_rules.is_empty() {
+        println!("    nothing to mount");
+    }
+    for rule in &final_rules {
+        let resolved_rules = match rule.resolve(&fs) {
+            Ok(resolved_rules) => resolved_rules,
+            Err(e) => {
+                println!("    {}: {e}", rule.name);
+                continue;
+            }
+        };
+        if resolved_rules.is_empty() {
+            println!("    {}: skipped, a path is missing", rule.name);
+        }
+        for resolved in resolved_rules {
+            let target = fs.fully_expand_path(&resolved.target)?;
+            let mut line = format!("    {:<9} {}", resolved.mode, target.display());
+            if !matches!(resolved.mode, RuleMode::Tmpfs | RuleMode::Hide) {
+                let rewrite = fs.fully_expand_path(&resolved.rewrite)?;
+                line.push_str(&format!(" -> {}", rewrite.display()));
+            }
+            if let Some(size) = &resolved.size {
+                line.push_str(&format!(" (size: {size})"));
+            }
+            if !resolved.options.is_empty() {
+                let options: Vec<_> = resolved.options.iter().map(|o| o.to_string()).collect();
+                line.push_str(&format!(" [{}]", options.join(", ")));
+            }
+            println!("{line}");
+        }
+    }
+    if cfg.immutable_root || profile.immutable {
+        println!("    everything else is mounted read-only");
+    }
+
+    Ok(())
+}
+
 fn scan_homedir(apps: Vec<App>) -> Result<()> {
     if !apps.is_empty() {
         info!(
             "found {} applications that might be boxxable! generating config...",
             apps.len()
         );
-        let mut rules = vec![];
-        for app in apps {
-            for fix in app.fixes {
-                let (old, new) = fix.split_once(':').unwrap();
-                let path = PathBuf::from(old);
-                let mode = if path.is_dir() {
-                    RuleMode::Directory
-                } else {
-                    RuleMode::File
-                };
-                rules.push(Rule {
-                    name: app.name.clone(),
-                    target: old.into(),
-                    rewrite: new.into(),
-                    mode,
-                    context: vec![],
-                    only: vec![],
-                    // TODO: populate for apps where possible
-                    env: HashMap::new(),
-                });
-            }
-        }
+        let rules: Vec<_> = apps.iter().flat_map(App::rules).collect();
         let config = BoxxyRules {
             rules: rules.clone(),
+            ..Default::default()
         };
         let config = &serde_yaml::to_string(&config)?;
         let mut printer = bat::PrettyPrinter::new();
@@ -0,0 +1,449 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use color_eyre::Result;
+use eyre::eyre;
+use log::*;
+use owo_colors::OwoColorize;
+use serde::{Deserialize, Serialize};
+use similar::TextDiff;
+
+use crate::enclosure::fs::FsDriver;
+use crate::enclosure::rule::{Rule, RuleMode};
+
+/// Every migration that has been run and not undone yet, oldest first.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Journal {
+    pub migrations: Vec<Migration>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Migration {
+    /// Unix timestamp of when the migration was run.
+    pub timestamp: u64,
+    pub moves: Vec<MigratedPath>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MigratedPath {
+    /// The name of the rule that caused the move.
+    pub rule: String,
+    pub target: PathBuf,
+    pub rewrite: PathBuf,
+}
+
+/// Moves existing targets into their rewrites, so that boxed programs keep
+/// seeing the files they already had.
+pub struct Migrator {
+    fs: FsDriver,
+    dry_run: bool,
+    journal_path: PathBuf,
+}
+
+impl Migrator {
+    pub fn new(dry_run: bool) -> Result<Self> {
+        Ok(Self {
+            fs: FsDriver::new(),
+            dry_run,
+            journal_path: Self::default_journal_path()?,
+        })
+    }
+
+    pub fn default_journal_path() -> Result<PathBuf> {
+        let state_dir = dirs::state_dir()
+            .or_else(dirs::data_local_dir)
+            .ok_or_else(|| eyre!("could not find a state directory for the migration journal"))?;
+        Ok(state_dir.join("boxxy").join("migrations.json"))
+    }
+
+    #[cfg(test)]
+    fn with_journal_path(mut self, journal_path: PathBuf) -> Self {
+        self.journal_path = journal_path;
+        self
+    }
+
+    /// Move every rule's target into its rewrite. Targets whose rewrite
+    /// already has contents are reported as conflicts and left alone.
+    pub fn migrate(&self, rules: &[Rule]) -> Result<()> {
+        let mut moves = vec![];
+        let mut conflicts = 0;
+
+        // Whatever was moved before an error still has to be journaled, so
+        // that it can be undone.
+        let result = self.migrate_rules(rules, &mut moves, &mut conflicts);
+        if !self.dry_run && !moves.is_empty() {
+            let mut journal = self.load_journal()?;
+            journal.migrations.push(Migration {
+                timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+                moves: moves.clone(),
+            });
+            self.save_journal(&journal)?;
+            info!(
+                "recorded migration in {}, undo it with `boxxy migrate --undo`",
+                self.journal_path.display()
+            );
+        }
+        result?;
+
+        info!(
+            "{} path(s) {}, {conflicts} conflict(s)",
+            moves.len(),
+            if self.dry_run {
+                "would be migrated"
+            } else {
+                "migrated"
+            }
+        );
+        if conflicts > 0 {
+            warn!("resolve the conflicts above by hand, then run `boxxy migrate` again");
+        }
+
+        Ok(())
+    }
+
+    fn migrate_rules(
+        &self,
+        rules: &[Rule],
+        moves: &mut Vec<MigratedPath>,
+        conflicts: &mut usize,
+    ) -> Result<()> {
+        for rule in rules {
+            if matches!(
+                rule.mode,
+                RuleMode::Overlay | RuleMode::Tmpfs | RuleMode::Hide
+            ) {
+                debug!("{}: {:?} rules don't need migrating", rule.name, rule.mode);
+                continue;
+            }
+
+            for rule in rule.expand_target(&self.fs)? {
+                let target = PathBuf::from(shellexpand::tilde(&rule.target).to_string());
+                let rewrite = self.fs.fully_expand_path(&rule.rewrite)?;
+
+                if self.fs.is_empty(&target)? {
+                    debug!("{}: nothing to migrate at {target:?}", rule.name);
+                    continue;
+                }
+                if self.fs.fully_expand_path(&rule.target)? == rewrite {
+                    info!(
+                        "{}: {} already points at {}",
+                        rule.name,
+                        target.display(),
+                        rewrite.display()
+                    );
+                    continue;
+                }
+                if !self.fs.is_empty(&rewrite)? {
+                    *conflicts += 1;
+                    self.report_conflict(&rule, &target, &rewrite)?;
+                    continue;
+                }
+
+                info!(
+                    "{}: {}{} -> {}",
+                    rule.name,
+                    if self.dry_run { "(dry run) " } else { "" },
+                    target.display(),
+                    rewrite.display()
+                );
+                if !self.dry_run {
+                    self.move_into_place(&target, &rewrite)?;
+                }
+                moves.push(MigratedPath {
+                    rule: rule.name.clone(),
+                    target,
+                    rewrite,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reverse the most recent migration in the journal. Paths whose
+    /// original location has been filled in since are left where they are.
+    pub fn undo(&self) -> Result<()> {
+        let mut journal = self.load_journal()?;
+        let Some(migration) = journal.migrations.pop() else {
+            info!("no migrations to undo");
+            return Ok(());
+        };
+
+        let mut remaining = vec![];
+        for moved in migration.moves.into_iter().rev() {
+            if !self.fs.is_empty(&moved.target)? {
+                warn!(
+                    "{}: {} has contents again, not moving {} back",
+                    moved.rule,
+                    moved.target.display(),
+                    moved.rewrite.display()
+                );
+                remaining.push(moved);
+                continue;
+            }
+            if !moved.rewrite.exists() {
+                warn!(
+                    "{}: {} no longer exists, skipping",
+                    moved.rule,
+                    moved.rewrite.display()
+                );
+                continue;
+            }
+
+            info!(
+                "{}: {}{} -> {}",
+                moved.rule,
+                if self.dry_run { "(dry run) " } else { "" },
+                moved.rewrite.display(),
+                moved.target.display()
+            );
+            if !self.dry_run {
+                self.move_into_place(&moved.rewrite, &moved.target)?;
+            }
+        }
+
+        if self.dry_run {
+            return Ok(());
+        }
+        if !remaining.is_empty() {
+            remaining.reverse();
+            journal.migrations.push(Migration {
+                timestamp: migration.timestamp,
+                moves: remaining,
+            });
+        }
+        self.save_journal(&journal)
+    }
+
+    fn move_into_place(&self, src: &Path, target: &Path) -> Result<()> {
+        if let Some(parent) = target.parent() {
+            self.fs.touch_dir(parent)?;
+        }
+        // An empty placeholder, ie one left behind by an earlier boxxy run,
+        // would stop the rename.
+        if target.is_dir() {
+            fs::remove_dir(target)?;
+        } else if target.exists() {
+            fs::remove_file(target)?;
+        }
+        self.fs.move_path(src, target)
+    }
+
+    fn report_conflict(&self, rule: &Rule, target: &Path, rewrite: &Path) -> Result<()> {
+        warn!(
+            "{}: both {} and {} have contents, not migrating!",
+            rule.name,
+            target.display(),
+            rewrite.display()
+        );
+
+        if target.is_file() && rewrite.is_file() {
+            match (fs::read_to_string(target), fs::read_to_string(rewrite)) {
+                (Ok(old), Ok(new)) if old == new => {
+                    info!("{}: the files are identical", rule.name);
+                }
+                (Ok(old), Ok(new)) => {
+                    let diff = TextDiff::from_lines(&old, &new);
+                    let diff = diff
+                        .unified_diff()
+                        .header(&target.to_string_lossy(), &rewrite.to_string_lossy())
+                        .to_string();
+                    for line in diff.lines() {
+                        print_diff_line(line);
+                    }
+                }
+                _ => println!(
+                    "binary files {} and {} differ",
+                    target.display(),
+                    rewrite.display()
+                ),
+            }
+            return Ok(());
+        }
+
+        println!("--- {}", target.display());
+        println!("+++ {}", rewrite.display());
+        let target_files = list_files(target)?;
+        let rewrite_files = list_files(rewrite)?;
+        for path in target_files.union(&rewrite_files) {
+            let line = match (target_files.contains(path), rewrite_files.contains(path)) {
+                (true, false) => format!("-{}", path.display()),
+                (false, true) => format!("+{}", path.display()),
+                _ if fs::read(target.join(path)).ok() != fs::read(rewrite.join(path)).ok() => {
+                    format!("~{}", path.display())
+                }
+                _ => continue,
+            };
+            print_diff_line(&line);
+        }
+        Ok(())
+    }
+
+    fn load_journal(&self) -> Result<Journal> {
+        if !self.journal_path.exists() {
+            return Ok(Journal::default());
+        }
+        Ok(serde_json::from_str(&fs::read_to_string(
+            &self.journal_path,
+        )?)?)
+    }
+
+    fn save_journal(&self, journal: &Journal) -> Result<()> {
+        if let Some(parent) = self.journal_path.parent() {
+            self.fs.touch_dir(parent)?;
+        }
+        fs::write(&self.journal_path, serde_json::to_string_pretty(journal)?)?;
+        Ok(())
+    }
+}
+
+fn print_diff_line(line: &str) {
+    let stdout = owo_colors::Stream::Stdout;
+    if line.starts_with("---") || line.starts_with("+++") {
+        println!("{}", line.if_supports_color(stdout, |text| text.bold()));
+    } else if line.starts_with('-') {
+        println!("{}", line.if_supports_color(stdout, |text| text.red()));
+    } else if line.starts_with('+') {
+        println!("{}", line.if_supports_color(stdout, |text| text.green()));
+    } else if line.starts_with('~') || line.starts_with("@@") {
+        println!("{}", line.if_supports_color(stdout, |text| text.yellow()));
+    } else {
+        println!("{line}");
+    }
+}
+
+/// Every file under a path, relative to it. A file is listed as its own
+/// file name.
+fn list_files(path: &Path) -> Result<BTreeSet<PathBuf>> {
+    let mut files = BTreeSet::new();
+    if !path.is_dir() {
+        if let Some(name) = path.file_name() {
+            files.insert(PathBuf::from(name));
+        }
+        return Ok(files);
+    }
+
+    let mut pending = vec![PathBuf::new()];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(path.join(&dir))? {
+            let entry = entry?;
+            let relative = dir.join(entry.file_name());
+            if entry.file_type()?.is_dir() {
+                pending.push(relative);
+            } else {
+                files.insert(relative);
+            }
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(target: &Path, rewrite: &Path) -> Rule {
+        Rule {
+            name: "test".into(),
+            target: target.to_string_lossy().to_string(),
+            rewrite: rewrite.to_string_lossy().to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_migrate_and_undo() -> Result<()> {
+        let fs = FsDriver::new();
+        let root_name = "test-migrate-and-undo";
+        let root = fs.container_root(root_name);
+        fs.setup_root(root_name)?;
+
+        let target = root.join("target");
+        let rewrite = root.join("nested").join("rewrite");
+        fs.touch_dir(&target)?;
+        fs::write(target.join("config"), "hello")?;
+        let migrator = Migrator::new(false)?.with_journal_path(root.join("journal.json"));
+
+        migrator.migrate(&[rule(&target, &rewrite)])?;
+        let moved = !target.exists() && fs::read_to_string(rewrite.join("config"))? == "hello";
+        migrator.undo()?;
+        let restored = !rewrite.exists() && fs::read_to_string(target.join("config"))? == "hello";
+        let journal = migrator.load_journal()?;
+
+        fs.cleanup_root(root_name)?;
+        assert!(moved);
+        assert!(restored);
+        assert!(journal.migrations.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_migration_can_be_undone() -> Result<()> {
+        let fs = FsDriver::new();
+        let root_name = "test-failed-migration-undo";
+        let root = fs.container_root(root_name);
+        fs.setup_root(root_name)?;
+
+        let first = root.join("first");
+        let second = root.join("second");
+        let blocker = root.join("blocker");
+        fs::write(&first, "first")?;
+        fs::write(&second, "second")?;
+        // The second rewrite can't be created inside of a file.
+        fs::write(&blocker, "")?;
+        let migrator = Migrator::new(false)?.with_journal_path(root.join("journal.json"));
+
+        let migrated = migrator.migrate(&[
+            rule(&first, &root.join("first-rewrite")),
+            rule(&second, &blocker.join("second-rewrite")),
+        ]);
+        let undone = migrator.undo();
+        let restored = fs::read_to_string(&first).ok();
+        let first_rewrite_exists = root.join("first-rewrite").exists();
+
+        fs.cleanup_root(root_name)?;
+        assert!(migrated.is_err());
+        undone?;
+        assert_eq!(restored.as_deref(), Some("first"));
+        assert!(!first_rewrite_exists);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate_skips_conflicts_and_dry_runs() -> Result<()> {
+        let fs = FsDriver::new();
+        let root_name = "test-migrate-skips-conflicts";
+        let root = fs.container_root(root_name);
+        fs.setup_root(root_name)?;
+
+        let conflicting = root.join("conflicting");
+        let existing = root.join("existing");
+        let dry = root.join("dry");
+        fs::write(&conflicting, "old")?;
+        fs::write(&existing, "new")?;
+        fs::write(&dry, "dry")?;
+        let journal_path = root.join("journal.json");
+
+        Migrator::new(false)?
+            .with_journal_path(journal_path.clone())
+            .migrate(&[rule(&conflicting, &existing)])?;
+        Migrator::new(true)?
+            .with_journal_path(journal_path.clone())
+            .migrate(&[rule(&dry, &root.join("dry-rewrite"))])?;
+        let untouched = fs::read_to_string(&conflicting)? == "old"
+            && fs::read_to_string(&existing)? == "new"
+            && dry.exists()
+            && !root.join("dry-rewrite").exists();
+        let journaled = journal_path.exists();
+
+        fs.cleanup_root(root_name)?;
+        assert!(untouched);
+        assert!(!journaled);
+
+        Ok(())
+    }
+}
@@ -3,6 +3,8 @@ use std::path::PathBuf;
 use color_eyre::Result;
 use serde::{Deserialize, Serialize};
 
+use crate::enclosure::rule::{Rule, RuleMode};
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct App {
     pub name: String,
@@ -10,6 +12,33 @@ pub struct App {
     pub fixes: Vec<String>,
 }
 
+impl App {
+    /// Turn this app's fixes into boxxy rules. Each rule is named after the
+    /// app and the path it fixes, since rules with the same name override
+    /// each other.
+    pub fn rules(&self) -> Vec<Rule> {
+        let mut rules = vec![];
+        for fix in &self.fixes {
+            let (old, new) = fix.split_once(':').unwrap();
+            let path = PathBuf::from(shellexpand::tilde(old).to_string());
+            let mode = if path.is_dir() {
+                RuleMode::Directory
+            } else {
+                RuleMode::File
+            };
+            rules.push(Rule {
+                name: format!("{}: {old}", self.name),
+                target: old.into(),
+                rewrite: new.into(),
+                mode,
+                // TODO: populate env for apps where possible
+                ..Default::default()
+            });
+        }
+        rules
+    }
+}
+
 pub struct Scanner {
     pub apps: Vec<App>,
 }
@@ -37,6 +66,7 @@ impl Scanner {
                 let path = shellexpand::full(&path)?.to_string();
                 if PathBuf::from(path).exists() {
                     out.push(app.clone());
+                    break;
                 }
             }
         }
@@ -44,3 +74,24 @@ impl Scanner {
         Ok(out)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_app_rules_have_unique_names() {
+        let app = App {
+            name: "vim".into(),
+            paths: vec![],
+            fixes: vec![
+                "~/.vimrc:$XDG_CONFIG_HOME/vim/vimrc".into(),
+                "~/.viminfo:$XDG_STATE_HOME/vim/viminfo".into(),
+            ],
+        };
+
+        let names: Vec<_> = app.rules().into_iter().map(|rule| rule.name).collect();
+
+        assert_eq!(names, vec!["vim: ~/.vimrc", "vim: ~/.viminfo"]);
+    }
+}